@@ -2,12 +2,16 @@ use anyhow::Result;
 use cargo_metadata::{Metadata, MetadataCommand, Package};
 use clap::{Parser, Subcommand};
 use colored::Colorize;
-use ocipkg::ImageName;
+use ocipkg::{
+    image::{LibraryKind, LinkLibrary, LinkManifest},
+    ImageName,
+};
 use std::{
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
+    io::BufReader,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
 };
 
 #[derive(Parser, Debug)]
@@ -137,6 +141,52 @@ fn generate_oci_archive_filename(
     format!("ocipkg_{:x}.tar", hash)
 }
 
+/// Build the library of the package, and returns system libraries required to link it as staticlib
+///
+/// The libraries are reported by `rustc --print=native-static-libs` in the same build,
+/// so that they always match the packed library.
+fn build_lib(package: &Package, release: bool) -> Result<Vec<String>> {
+    let mut cmd = Command::new("cargo");
+    cmd.args(["rustc", "--lib", "--message-format=json"]);
+    if release {
+        cmd.arg("--release");
+    }
+    let mut child = cmd
+        .args(["--manifest-path", package.manifest_path.as_str()])
+        .args(["--", "--print=native-static-libs"])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut libs = Vec::new();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    for message in cargo_metadata::Message::parse_stream(stdout) {
+        let cargo_metadata::Message::CompilerMessage(msg) = message? else {
+            continue;
+        };
+        let Some(flags) = msg.message.message.strip_prefix("native-static-libs:") else {
+            if let Some(rendered) = &msg.message.rendered {
+                eprint!("{rendered}");
+            }
+            continue;
+        };
+        let mut flags = flags.split_whitespace();
+        while let Some(flag) = flags.next() {
+            if let Some(name) = flag.strip_prefix("-l") {
+                libs.push(name.to_string());
+            } else if flag == "-framework" {
+                if let Some(name) = flags.next() {
+                    libs.push(format!("framework={name}"));
+                }
+            } else if let Some(name) = flag.strip_suffix(".lib") {
+                libs.push(name.to_string());
+            }
+        }
+    }
+    if !child.wait()?.success() {
+        anyhow::bail!("cargo rustc failed");
+    }
+    Ok(libs)
+}
+
 fn main() -> Result<()> {
     env_logger::Builder::new()
         .filter_level(log::LevelFilter::Info)
@@ -158,31 +208,36 @@ fn main() -> Result<()> {
                 generate_image_name(&package)
             };
 
-            let mut cmd = Command::new("cargo");
-            cmd.arg("build");
-            if release {
-                cmd.arg("--release");
-            }
-            cmd.args(["--manifest-path", package.manifest_path.as_str()])
-                .status()?;
+            let native_static_libs = build_lib(&package, release)?;
 
-            for target in package.targets {
+            for target in &package.targets {
                 let mut targets = Vec::new();
-                for ty in &target.crate_types {
-                    // FIXME support non-Linux OS
-                    match ty.to_string().as_str() {
-                        "staticlib" => {
-                            targets.push(
-                                build_dir.join(format!("lib{}.a", target.name.replace('-', "_"))),
-                            );
-                        }
-                        "cdylib" => {
-                            targets.push(
-                                build_dir.join(format!("lib{}.so", target.name.replace('-', "_"))),
-                            );
-                        }
-                        _ => {}
-                    }
+                let mut link = LinkManifest::default();
+                let lib_name = target.name.replace('-', "_");
+                let crate_types: Vec<String> =
+                    target.crate_types.iter().map(|ty| ty.to_string()).collect();
+                let has = |ty: &str| crate_types.iter().any(|t| t == ty);
+                // FIXME support non-Linux OS
+                if has("staticlib") {
+                    targets.push(build_dir.join(format!("lib{lib_name}.a")));
+                    link.system_libraries = native_static_libs.clone();
+                }
+                if has("cdylib") {
+                    targets.push(build_dir.join(format!("lib{lib_name}.so")));
+                }
+                // Static library is preferred if both are built
+                let kind = if has("staticlib") {
+                    Some(LibraryKind::Static)
+                } else if has("cdylib") {
+                    Some(LibraryKind::Dylib)
+                } else {
+                    None
+                };
+                if let Some(kind) = kind {
+                    link.libraries.push(LinkLibrary {
+                        name: lib_name.clone(),
+                        kind,
+                    });
                 }
                 if targets.is_empty() {
                     panic!("No target exists for packing. Only staticlib or cdylib are suppoted.");
@@ -200,7 +255,7 @@ fn main() -> Result<()> {
                     annotations.authors = Some(package.authors.join(","))
                 }
 
-                let dest = build_dir.join(generate_oci_archive_filename(&image_name, &target));
+                let dest = build_dir.join(generate_oci_archive_filename(&image_name, target));
                 eprintln!(
                     "{:>12} oci-archive ({})",
                     "Creating".green().bold(),
@@ -208,6 +263,7 @@ fn main() -> Result<()> {
                 );
                 let mut b = ocipkg::image::Builder::new(dest, image_name.clone())?;
                 b.append_files(&targets)?;
+                b.set_link(link);
                 let _artifact = b.build()?;
             }
         }
@@ -1,6 +1,10 @@
 use anyhow::{bail, Context, Result};
 use clap::Parser;
-use ocipkg::image::{Artifact, Image};
+use ocipkg::{
+    distribution::{CopyImage, ImageLocation, Mirror, MirrorDestination, StoredAuth},
+    encryption::{PrivateKey, PublicKey},
+    image::{Artifact, ArtifactVersion, Compression, FileType, Image},
+};
 use std::path::*;
 
 #[derive(Debug, Parser)]
@@ -11,12 +15,23 @@ enum Opt {
         /// Path of input directory to be packed
         input_directory: PathBuf,
 
-        /// Path of output tar archive in oci-archive format
+        /// Path of output tar archive in oci-archive format, compressed by gzip or zstd if it ends with `.tar.gz` or `.tar.zst`
         output: PathBuf,
 
         /// Name of container, use UUID v4 hyphenated if not set.
         #[arg(short = 't', long = "tag")]
         tag: Option<String>,
+
+        /// Encrypt layers for the recipient, e.g. `ocipkg-pub:...`. Can be specified multiple times.
+        #[arg(short = 'r', long = "recipient")]
+        recipients: Vec<PublicKey>,
+
+        /// Compression of layers in the form of `{none|gzip|zstd}[:level]`
+        #[arg(long = "compression", default_value = "gzip")]
+        compression: Compression,
+
+        #[command(flatten)]
+        link: LinkArgs,
     },
 
     /// Compose files into an oci-archive tar file
@@ -24,13 +39,24 @@ enum Opt {
         /// Path of input file to be packed
         inputs: Vec<PathBuf>,
 
-        /// Path of output tar archive in oci-archive format
+        /// Path of output tar archive in oci-archive format, compressed by gzip or zstd if it ends with `.tar.gz` or `.tar.zst`
         #[arg(short = 'o', long = "output")]
         output: PathBuf,
 
         /// Name of container, use UUID v4 hyphenated if not set.
         #[arg(short = 't', long = "tag")]
         tag: Option<String>,
+
+        /// Encrypt layers for the recipient, e.g. `ocipkg-pub:...`. Can be specified multiple times.
+        #[arg(short = 'r', long = "recipient")]
+        recipients: Vec<PublicKey>,
+
+        /// Compression of layers in the form of `{none|gzip|zstd}[:level]`
+        #[arg(long = "compression", default_value = "gzip")]
+        compression: Compression,
+
+        #[command(flatten)]
+        link: LinkArgs,
     },
 
     /// Compose a static-linked executable file into an oci-archive tar file
@@ -53,7 +79,7 @@ enum Opt {
 
     /// Load and expand container local cache
     Load {
-        /// Input oci-archive
+        /// Input oci-archive or docker-archive
         input: PathBuf,
 
         /// Overwrite existing local cache
@@ -61,25 +87,152 @@ enum Opt {
         overwrite: bool,
     },
 
-    /// Get and save in local storage
+    /// Get and save in local storage. Version requirement like `ghcr.io/org/lib:^1.2` is resolved by tags in registry.
     Get {
         image_name: String,
         #[clap(short = 'f', long = "overwrite")]
         overwrite: bool,
     },
 
-    /// Push oci-archive to registry
+    /// Push oci-archive or docker-archive to registry
     Push {
+        /// Input oci-archive or docker-archive
+        input: PathBuf,
+    },
+
+    /// Copy an image between registries, oci-dirs, and archives, e.g. `ocipkg copy ghcr.io/org/lib:1.0 oci-archive:lib.tar`
+    Copy {
+        /// Source in the form of `[docker://]{image_name}`, `oci:{path}`, `oci-archive:{path}`, or `docker-archive:{path}`
+        src: ImageLocation,
+
+        /// Destination in the same form as the source
+        dst: ImageLocation,
+
+        /// Copy manifests referring the image, e.g. signatures and SBOMs. Only supported between registries.
+        #[arg(long = "referrers")]
+        referrers: bool,
+
+        /// Credentials for the source registry in the form of `{username}:{password}`
+        #[arg(long = "src-creds")]
+        src_creds: Option<String>,
+
+        /// Credentials for the destination registry in the form of `{username}:{password}`
+        #[arg(long = "dest-creds")]
+        dest_creds: Option<String>,
+    },
+
+    /// Mirror tags of repositories into a registry or an oci-dir, e.g. `ocipkg mirror ghcr.io/org/lib --to localhost:5000/backup`
+    Mirror {
+        /// Repositories to be mirrored, e.g. `ghcr.io/org/lib`
+        #[arg(required = true)]
+        repositories: Vec<String>,
+
+        /// Destination in the form of `[docker://]{registry}[/{prefix}]` or `oci:{path}`
+        #[arg(long = "to")]
+        to: MirrorDestination,
+
+        /// Mirror only tags matching the regular expression
+        #[arg(long = "tag-regex")]
+        tag_regex: Option<regex::Regex>,
+
+        /// Mirror only tags satisfying the semver requirement, e.g. `>=1.2`
+        #[arg(long = "semver")]
+        semver: Option<semver::VersionReq>,
+
+        /// Copy tags again if their digests differ from the source
+        #[arg(long = "sync")]
+        sync: bool,
+
+        /// Credentials for the source registries in the form of `{username}:{password}`
+        #[arg(long = "src-creds")]
+        src_creds: Option<String>,
+
+        /// Credentials for the destination registry in the form of `{username}:{password}`
+        #[arg(long = "dest-creds")]
+        dest_creds: Option<String>,
+    },
+
+    /// Rename the image in oci-archive in place
+    Tag {
         /// Input oci-archive
         input: PathBuf,
+
+        /// New name of the image
+        new_name: String,
+
+        /// Name of the image to be renamed if the archive contains multiple images
+        #[arg(long = "from")]
+        from: Option<String>,
     },
 
     /// Get image directory to be used by ocipkg for given container name
+    #[rustfmt::skip]
     ImageDirectory {
         image_name: String,
     },
 
-    List,
+    /// List images in local storage
+    List {
+        /// Show only images from the registry, e.g. `ghcr.io`
+        #[arg(long = "registry")]
+        registry: Option<String>,
+
+        /// Show only images in the repository, e.g. `ghcr.io/termoshtt/ocipkg/dynamic/rust`
+        #[arg(long = "repository")]
+        repository: Option<String>,
+
+        /// Output format
+        #[arg(long = "format", value_enum, default_value = "table")]
+        format: ListFormat,
+
+        /// Show only image names
+        #[arg(short = 'q', long = "quiet")]
+        quiet: bool,
+    },
+
+    /// Remove images from local storage
+    Rm {
+        #[arg(required = true)]
+        image_names: Vec<String>,
+    },
+
+    /// Remove old images and unreferenced blobs from local storage
+    Prune {
+        /// Remove images stored before this duration, e.g. `30days`
+        #[arg(long = "older-than")]
+        older_than: Option<humantime::Duration>,
+
+        /// Keep the N most recently stored images for each repository
+        #[arg(long = "keep")]
+        keep: Option<usize>,
+
+        /// Remove blobs not referred by any image
+        #[arg(long = "blobs")]
+        blobs: bool,
+
+        /// Only show what would be removed
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+    },
+
+    /// Show disk usage of local storage
+    Du,
+
+    /// Manage `ocipkg.lock` pinning images to digests
+    Lock {
+        #[command(subcommand)]
+        command: LockCommand,
+    },
+
+    /// Check integrity of images in local storage, or of an oci-dir
+    Verify {
+        /// Image name in local storage, or path of image directory or oci-dir. All images in local storage if not set.
+        target: Option<String>,
+
+        /// Re-fetch corrupted images from registry
+        #[arg(long = "repair")]
+        repair: bool,
+    },
 
     /// Login to OCI registry
     Login {
@@ -93,9 +246,115 @@ enum Opt {
 
     /// Inspect components in OCI archive
     Inspect {
-        /// Input oci-archive
+        /// Input oci-archive or docker-archive
         input: PathBuf,
     },
+
+    /// Generate pkg-config file `{name}.pc` for an image in local storage
+    ExportPkgconfig {
+        /// Image name, fetched into local storage if missing
+        image_name: String,
+
+        /// Package name. Last component of the image name if not set.
+        #[arg(long = "name")]
+        name: Option<String>,
+
+        /// Output directory. Default is `{data_dir}/.export/pkgconfig` to be added to `PKG_CONFIG_PATH`
+        #[arg(short = 'o', long = "output")]
+        output: Option<PathBuf>,
+    },
+
+    /// Generate CMake package files `{name}/{name}Config.cmake` for an image in local storage
+    ExportCmake {
+        /// Image name, fetched into local storage if missing
+        image_name: String,
+
+        /// Package name. Last component of the image name if not set.
+        #[arg(long = "name")]
+        name: Option<String>,
+
+        /// Output directory. Default is `{data_dir}/.export/cmake` to be added to `CMAKE_PREFIX_PATH`
+        #[arg(short = 'o', long = "output")]
+        output: Option<PathBuf>,
+    },
+
+    /// Extract files matching glob patterns from an artifact without unpacking everything
+    Extract {
+        /// Image name in registry, or path of oci-archive or oci-dir
+        image: String,
+
+        /// Glob patterns of files to be extracted, e.g. `include/**/*.h`
+        #[arg(required = true)]
+        patterns: Vec<String>,
+
+        /// Output directory
+        #[arg(short = 'o', long = "output", default_value = ".")]
+        output: PathBuf,
+    },
+
+    /// Generate a key pair for layer encryption, and print the public key
+    Keygen {
+        /// Path to save the private key. Default is `$XDG_CONFIG_HOME/ocipkg/key.txt`
+        #[arg(short = 'o', long = "output")]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum LockCommand {
+    /// Resolve digests of images in registry and record them into the lock file
+    Update {
+        /// Images to be pinned, possibly with version requirement like `ghcr.io/org/lib:^1.2`.
+        /// All images in the lock file are refreshed if not set.
+        image_names: Vec<String>,
+
+        /// Path of the lock file
+        #[arg(long = "lockfile", default_value = ocipkg::lock::LOCK_FILE_NAME)]
+        lockfile: PathBuf,
+    },
+}
+
+/// Link instructions recorded in the artifact, see [ocipkg::image::LinkManifest]
+#[derive(Debug, clap::Args)]
+struct LinkArgs {
+    /// Library in the artifact to be linked in the form of `[static|dylib=]name`, e.g. `dylib=foo` for `libfoo.so`
+    #[arg(long = "link")]
+    libraries: Vec<ocipkg::image::LinkLibrary>,
+
+    /// Directory in the artifact searched by the linker. The top directory if not set.
+    #[arg(long = "link-search")]
+    search_paths: Vec<PathBuf>,
+
+    /// Directory in the artifact containing headers. `include` if not set.
+    #[arg(long = "link-include")]
+    include_paths: Vec<PathBuf>,
+
+    /// Library provided by the system required to link, e.g. `stdc++` or `pthread`
+    #[arg(long = "link-system")]
+    system_libraries: Vec<String>,
+
+    /// Configuration enabled for crates linking the artifact, e.g. `has_foo` or `foo_version="2"`
+    #[arg(long = "link-cfg")]
+    cfgs: Vec<String>,
+}
+
+impl LinkArgs {
+    fn manifest(self) -> Option<ocipkg::image::LinkManifest> {
+        let manifest = ocipkg::image::LinkManifest {
+            libraries: self.libraries,
+            search_paths: self.search_paths,
+            include_paths: self.include_paths,
+            system_libraries: self.system_libraries,
+            cfgs: self.cfgs,
+        };
+        (!manifest.is_empty()).then_some(manifest)
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum ListFormat {
+    Table,
+    Json,
 }
 
 fn main() -> Result<()> {
@@ -109,15 +368,27 @@ fn main() -> Result<()> {
             input_directory,
             output,
             tag,
+            recipients,
+            compression,
+            link,
         } => {
             let mut output = output;
-            output.set_extension("tar");
+            if Compression::from_extension(&output) == Compression::None {
+                output.set_extension("tar");
+            }
             let image_name = if let Some(name) = tag {
                 ocipkg::ImageName::parse(&name)?
             } else {
                 ocipkg::ImageName::default()
             };
             let mut b = ocipkg::image::Builder::new(output, image_name)?;
+            b.set_compression(compression);
+            for recipient in recipients {
+                b.add_recipient(recipient);
+            }
+            if let Some(link) = link.manifest() {
+                b.set_link(link);
+            }
             b.append_dir_all(&input_directory)?;
             let _artifact = b.build()?;
         }
@@ -126,15 +397,27 @@ fn main() -> Result<()> {
             inputs,
             output,
             tag,
+            recipients,
+            compression,
+            link,
         } => {
             let mut output = output;
-            output.set_extension("tar");
+            if Compression::from_extension(&output) == Compression::None {
+                output.set_extension("tar");
+            }
             let image_name = if let Some(name) = tag {
                 ocipkg::ImageName::parse(&name)?
             } else {
                 ocipkg::ImageName::default()
             };
             let mut b = ocipkg::image::Builder::new(output, image_name)?;
+            b.set_compression(compression);
+            for recipient in recipients {
+                b.add_recipient(recipient);
+            }
+            if let Some(link) = link.manifest() {
+                b.set_link(link);
+            }
             b.append_files(&inputs)?;
             let _artifact = b.build()?;
         }
@@ -174,7 +457,7 @@ fn main() -> Result<()> {
             image_name,
             overwrite,
         } => {
-            let image_name = ocipkg::ImageName::parse(&image_name)?;
+            let image_name = ocipkg::distribution::resolve_image_name(&image_name)?;
             ocipkg::distribution::get_image(&image_name, overwrite)?;
         }
 
@@ -182,15 +465,264 @@ fn main() -> Result<()> {
             ocipkg::distribution::push_image(&input)?;
         }
 
+        Opt::Copy {
+            src,
+            dst,
+            referrers,
+            src_creds,
+            dest_creds,
+        } => {
+            let report = CopyImage::new(src.clone(), dst.clone())
+                .src_auth(location_auth(&src, src_creds.as_deref())?)
+                .dst_auth(location_auth(&dst, dest_creds.as_deref())?)
+                .referrers(referrers)
+                .run()?;
+            println!("Copied {src} to {dst}: {report}");
+        }
+
+        Opt::Mirror {
+            repositories,
+            to,
+            tag_regex,
+            semver,
+            sync,
+            src_creds,
+            dest_creds,
+        } => {
+            let repositories = repositories
+                .iter()
+                .map(|name| ocipkg::ImageName::parse(name))
+                .collect::<Result<Vec<_>>>()?;
+            let dst_hostname = match &to {
+                MirrorDestination::Registry(prefix) => {
+                    ocipkg::ImageName::parse(&format!("{prefix}/mirror"))?.hostname
+                }
+                MirrorDestination::OciDir(_) => {
+                    if dest_creds.is_some() {
+                        bail!("Credentials are given for non-registry destination: {to}");
+                    }
+                    String::new()
+                }
+            };
+            let mut mirror = Mirror::new(to)
+                .src_auth(auth_with_creds(
+                    repositories.iter().map(|name| name.hostname.as_str()),
+                    src_creds.as_deref(),
+                )?)
+                .dst_auth(auth_with_creds(
+                    [dst_hostname.as_str()],
+                    dest_creds.as_deref(),
+                )?)
+                .sync(sync);
+            for repository in repositories {
+                mirror = mirror.repository(repository);
+            }
+            if let Some(tag_regex) = tag_regex {
+                mirror = mirror.tag_regex(tag_regex);
+            }
+            if let Some(semver) = semver {
+                mirror = mirror.version_req(semver);
+            }
+            let report = mirror.run()?;
+            println!("{report}");
+            if !report.failed.is_empty() {
+                bail!("Failed to mirror {} tags", report.failed.len());
+            }
+        }
+
+        Opt::Tag {
+            input,
+            new_name,
+            from,
+        } => {
+            let mut ar = ocipkg::image::OciArchive::new(&input)?;
+            if let Some(from) = from {
+                ar.select(&ocipkg::ImageName::parse(&from)?)?;
+            }
+            ar.retag(&ocipkg::ImageName::parse(&new_name)?)?;
+        }
+
         Opt::ImageDirectory { image_name } => {
             let image_name = ocipkg::ImageName::parse(&image_name)?;
             println!("{}", ocipkg::local::image_dir(&image_name)?.display());
         }
 
-        Opt::List => {
-            let images = ocipkg::local::get_image_list()?;
+        Opt::List {
+            registry,
+            repository,
+            format,
+            quiet,
+        } => {
+            let images: Vec<_> = ocipkg::local::get_image_list()?
+                .into_iter()
+                .filter(|info| registry.as_ref().is_none_or(|r| &info.registry() == r))
+                .filter(|info| {
+                    repository
+                        .as_ref()
+                        .is_none_or(|r| &info.repository() == r || info.name.name.as_str() == r)
+                })
+                .collect();
+            if quiet {
+                for info in images {
+                    println!("{}", info.name);
+                }
+                return Ok(());
+            }
+            let pulled_at = |info: &ocipkg::local::ImageInfo| {
+                info.pulled_at
+                    .map(|t| humantime::format_rfc3339_seconds(t).to_string())
+            };
+            match format {
+                ListFormat::Json => {
+                    let json: Vec<_> = images
+                        .iter()
+                        .map(|info| {
+                            serde_json::json!({
+                                "name": info.name.to_string(),
+                                "path": info.path,
+                                "digest": info.digest.as_ref().map(|d| d.to_string()),
+                                "artifactType": info.artifact_type.as_ref().map(|t| t.to_string()),
+                                "size": info.size,
+                                "pulledAt": pulled_at(info),
+                                "version": info.version,
+                                "revision": info.revision,
+                                "source": info.source,
+                            })
+                        })
+                        .collect();
+                    println!("{}", serde_json::to_string_pretty(&json)?);
+                }
+                ListFormat::Table => {
+                    println!(
+                        "{:<50} {:<19} {:>10} {:<20} VERSION",
+                        "NAME", "DIGEST", "SIZE", "PULLED"
+                    );
+                    for info in &images {
+                        let digest = info
+                            .digest
+                            .as_ref()
+                            .map(|d| format!("{}:{:.12}", d.algorithm(), d.digest()))
+                            .unwrap_or_default();
+                        println!(
+                            "{:<50} {:<19} {:>10} {:<20} {}",
+                            info.name.to_string(),
+                            digest,
+                            format_size(info.size),
+                            pulled_at(info).unwrap_or_default(),
+                            info.version.as_deref().unwrap_or("")
+                        );
+                    }
+                }
+            }
+        }
+
+        Opt::Rm { image_names } => {
+            for name in image_names {
+                let image_name = ocipkg::ImageName::parse(&name)?;
+                ocipkg::local::remove_image(&image_name)?;
+                println!("{image_name}");
+            }
+        }
+
+        Opt::Prune {
+            older_than,
+            keep,
+            blobs,
+            dry_run,
+        } => {
+            let report = ocipkg::local::prune(&ocipkg::local::PruneOptions {
+                older_than: older_than.map(Into::into),
+                keep_latest: keep,
+                blobs,
+                dry_run,
+            })?;
+            let verb = if dry_run { "Would remove" } else { "Removed" };
+            for image in &report.images {
+                println!("{verb} {image}");
+            }
+            println!(
+                "{verb} {} images and {} blobs ({})",
+                report.images.len(),
+                report.blobs.len(),
+                format_size(report.blobs_size)
+            );
+        }
+
+        Opt::Du => {
+            let usage = ocipkg::local::disk_usage()?;
+            for (image, size) in &usage.images {
+                println!("{:>10}  {image}", format_size(*size));
+            }
+            println!("{:>10}  (shared blobs)", format_size(usage.blob_store));
+            println!("{:>10}  (total)", format_size(usage.total));
+        }
+
+        Opt::Lock {
+            command:
+                LockCommand::Update {
+                    image_names,
+                    lockfile,
+                },
+        } => {
+            let mut lock = if lockfile.exists() {
+                ocipkg::lock::LockFile::load(&lockfile)?
+            } else {
+                ocipkg::lock::LockFile::default()
+            };
+            let image_names = image_names
+                .iter()
+                .map(|name| ocipkg::distribution::resolve_image_name(name))
+                .collect::<Result<Vec<_>>>()?;
+            if image_names.is_empty() && lock.images().is_empty() {
+                bail!("No image to be pinned in {}", lockfile.display());
+            }
+            lock.update(&image_names)?;
+            lock.save(&lockfile)?;
+            for image in lock.images() {
+                println!("{} {}", image.name, image.digest);
+            }
+        }
+
+        Opt::Verify { target, repair } => {
+            let path = target.as_ref().map(PathBuf::from);
+            if let Some(path) = path.filter(|path| path.is_dir()) {
+                if repair {
+                    bail!("--repair is available only for images in local storage");
+                }
+                if path.join(".oci-dir").is_dir() {
+                    ocipkg::local::verify_image_dir(&path)?;
+                } else {
+                    ocipkg::image::OciDir::new(&path)?.verify()?;
+                }
+                println!("OK {}", path.display());
+                return Ok(());
+            }
+
+            let images = match target {
+                Some(name) => vec![ocipkg::ImageName::parse(&name)?],
+                None => ocipkg::local::get_image_list()?
+                    .into_iter()
+                    .map(|info| info.name)
+                    .collect(),
+            };
+            let mut corrupted = 0;
             for image in images {
-                println!("{}", image);
+                match ocipkg::local::verify_image(&image) {
+                    Ok(()) => println!("OK {image}"),
+                    Err(e) if repair => {
+                        println!("Repairing {image}: {e}");
+                        ocipkg::local::repair_image(&image)?;
+                        ocipkg::local::verify_image(&image)?;
+                        println!("Repaired {image}");
+                    }
+                    Err(e) => {
+                        println!("Corrupted {image}: {e}");
+                        corrupted += 1;
+                    }
+                }
+            }
+            if corrupted > 0 {
+                bail!("{corrupted} corrupted images found. Use --repair to re-fetch them.");
             }
         }
 
@@ -221,18 +753,146 @@ fn main() -> Result<()> {
         }
 
         Opt::Inspect { input } => {
-            let mut ar = Artifact::from_oci_archive(&input)?;
+            let mut ar = Artifact::new(ocipkg::image::read_archive(&input)?)?;
             let image_name = ar.get_name()?;
             println!("[{image_name}]");
-            let files = ar.files()?;
-            for (i, path) in files.iter().enumerate() {
-                if i < files.len() - 1 {
-                    println!("  ├─ {}", path.display());
+            let lines: Vec<String> = match ar.version() {
+                ArtifactVersion::V0 => ar
+                    .files()?
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect(),
+                ArtifactVersion::V1 => {
+                    let config = ar.get_ocipkg_config()?;
+                    let mut entries: Vec<_> = config.entries().collect();
+                    entries.sort_by(|a, b| a.path.cmp(&b.path));
+                    entries
+                        .into_iter()
+                        .map(|entry| match (entry.file_type, entry.size, &entry.target) {
+                            (Some(FileType::Directory), _, _) => {
+                                format!("{}/", entry.path.display())
+                            }
+                            (Some(FileType::Symlink), _, Some(target)) => {
+                                format!("{} -> {}", entry.path.display(), target.display())
+                            }
+                            (_, Some(size), _) => {
+                                format!("{} ({} bytes)", entry.path.display(), size)
+                            }
+                            _ => entry.path.display().to_string(),
+                        })
+                        .collect()
+                }
+            };
+            for (i, line) in lines.iter().enumerate() {
+                if i < lines.len() - 1 {
+                    println!("  ├─ {}", line);
                 } else {
-                    println!("  └─ {}", path.display());
+                    println!("  └─ {}", line);
                 }
             }
         }
+
+        Opt::Extract {
+            image,
+            patterns,
+            output,
+        } => {
+            let mut ar = Artifact::new(ocipkg::image::read(&image)?)?;
+            let extracted = ar.extract(&patterns, &output)?;
+            if extracted.is_empty() {
+                bail!("No file matches in {image}");
+            }
+            for path in extracted {
+                println!("{}", output.join(path).display());
+            }
+        }
+
+        Opt::ExportPkgconfig {
+            image_name,
+            name,
+            output,
+        } => {
+            let image_name = get_if_missing(&image_name)?;
+            let path =
+                ocipkg::local::export_pkg_config(&image_name, name.as_deref(), output.as_deref())?;
+            println!("{}", path.display());
+        }
+
+        Opt::ExportCmake {
+            image_name,
+            name,
+            output,
+        } => {
+            let image_name = get_if_missing(&image_name)?;
+            let path =
+                ocipkg::local::export_cmake(&image_name, name.as_deref(), output.as_deref())?;
+            println!("{}", path.display());
+        }
+
+        Opt::Keygen { output } => {
+            let output = match output {
+                Some(output) => output,
+                None => PrivateKey::default_path()?,
+            };
+            let key = PrivateKey::generate();
+            key.save(&output)?;
+            log::info!("Private key saved to {}", output.display());
+            println!("{}", key.public_key());
+        }
     }
     Ok(())
 }
+
+/// Resolve the image name, and fetch it into local storage unless exists
+fn get_if_missing(image_name: &str) -> Result<ocipkg::ImageName> {
+    let image_name = ocipkg::distribution::resolve_image_name(image_name)?;
+    if !ocipkg::local::image_dir(&image_name)?.exists() {
+        ocipkg::distribution::get_image(&image_name, false)?;
+    }
+    Ok(image_name)
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Stored authentication info with credentials given in the form of `{username}:{password}` for the registries
+fn auth_with_creds<'a>(
+    hostnames: impl IntoIterator<Item = &'a str>,
+    creds: Option<&str>,
+) -> Result<StoredAuth> {
+    let mut auth = StoredAuth::load_all().unwrap_or_default();
+    if let Some(creds) = creds {
+        let (username, password) = creds
+            .split_once(':')
+            .context("Credentials must be in the form of `{username}:{password}`")?;
+        for hostname in hostnames {
+            auth.add(hostname, username, password);
+        }
+    }
+    Ok(auth)
+}
+
+/// Stored authentication info with credentials for the registry of the location
+fn location_auth(location: &ImageLocation, creds: Option<&str>) -> Result<StoredAuth> {
+    match location {
+        ImageLocation::Registry(image_name) => {
+            auth_with_creds([image_name.hostname.as_str()], creds)
+        }
+        _ if creds.is_some() => {
+            bail!("Credentials are given for non-registry location: {location}")
+        }
+        _ => auth_with_creds([], creds),
+    }
+}
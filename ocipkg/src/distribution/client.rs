@@ -1,9 +1,10 @@
-use crate::{digest::DigestExt, distribution::*, Digest, ImageName, Name, Reference};
-use anyhow::{bail, ensure, Result};
+use crate::{digest::DigestExt, distribution::*, local, Digest, ImageName, Name, Reference};
+use anyhow::{anyhow, bail, Result};
 use oci_spec::{
-    distribution::TagList,
-    image::{ImageManifest, ToDockerV2S2},
+    distribution::{ErrorResponse, TagList},
+    image::{Descriptor, ImageIndex, ImageManifest, ToDockerV2S2},
 };
+use std::str::FromStr;
 use url::Url;
 
 /// A client for `/v2/<name>/` API endpoint
@@ -29,7 +30,14 @@ impl Client {
         Self::new(image.registry_url()?, image.name.clone())
     }
 
+    /// Create a client. This fails in offline mode, see [crate::local::is_offline].
     pub fn new_with_auth(url: Url, name: Name, auth: StoredAuth) -> Result<Self> {
+        if local::is_offline() {
+            bail!(
+                "Cannot access registry {url} in offline mode ({})",
+                local::OFFLINE_ENV
+            );
+        }
         Ok(Client {
             agent: ureq::Agent::new(),
             url,
@@ -47,23 +55,51 @@ impl Client {
         self.auth.add(domain, username, password);
     }
 
-    fn call(&mut self, req: ureq::Request) -> Result<ureq::Response> {
-        if self.token.is_none() {
-            // Try get token
-            let try_req = req.clone();
-            let challenge = match try_req.call() {
-                Ok(res) => return Ok(res),
-                Err(e) => AuthChallenge::try_from(e)?,
+    /// Send a request with the cached token, and authenticate again if the registry requires
+    ///
+    /// The token is refreshed when its scope is insufficient, e.g. push after pull.
+    fn send(
+        &mut self,
+        req: ureq::Request,
+        body: Option<&[u8]>,
+    ) -> Result<Result<ureq::Response, Box<ureq::Error>>> {
+        let send = |req: ureq::Request, token: Option<&String>| {
+            let req = match token {
+                Some(token) => req.set("Authorization", &format!("Bearer {}", token)),
+                None => req,
             };
-            self.token = Some(self.auth.challenge(&challenge)?);
+            match body {
+                Some(body) => req.send_bytes(body),
+                None => req.call(),
+            }
+            .map_err(Box::new)
+        };
+        match send(req.clone(), self.token.as_ref()) {
+            Err(e) if matches!(&*e, ureq::Error::Status(401, res) if res.has("www-authenticate")) =>
+            {
+                let ureq::Error::Status(_, res) = *e else {
+                    unreachable!()
+                };
+                let challenge =
+                    AuthChallenge::from_header(res.header("www-authenticate").unwrap())?;
+                self.token = Some(self.auth.challenge(&challenge)?);
+                Ok(send(req, self.token.as_ref()))
+            }
+            res => Ok(res),
+        }
+    }
+
+    fn call(&mut self, req: ureq::Request) -> Result<ureq::Response> {
+        self.send(req, None)?.map_err(into_error)
+    }
+
+    /// Same as [Client::call], but returns `None` if the registry responds 404 Not Found
+    fn call_optional(&mut self, req: ureq::Request) -> Result<Option<ureq::Response>> {
+        match self.send(req, None)? {
+            Ok(res) => Ok(Some(res)),
+            Err(e) if matches!(*e, ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(into_error(e)),
         }
-        ensure!(self.token.is_some());
-        Ok(req
-            .set(
-                "Authorization",
-                &format!("Bearer {}", self.token.as_ref().unwrap()),
-            )
-            .call()?)
     }
 
     fn get(&self, url: &Url) -> ureq::Request {
@@ -71,6 +107,11 @@ impl Client {
         self.agent.get(url.as_str())
     }
 
+    fn head(&self, url: &Url) -> ureq::Request {
+        log::info!("HEAD {}", url);
+        self.agent.head(url.as_str())
+    }
+
     fn put(&self, url: &Url) -> ureq::Request {
         log::info!("PUT {}", url);
         self.agent.put(url.as_str())
@@ -87,12 +128,61 @@ impl Client {
     /// GET /v2/<name>/tags/list
     /// ```
     ///
+    /// All pages are fetched by following `Link` header, see [Client::get_tags_page].
+    ///
     /// See [corresponding OCI distribution spec document](https://github.com/opencontainers/distribution-spec/blob/main/spec.md#content-discovery) for detail.
     pub fn get_tags(&mut self) -> Result<Vec<String>> {
-        let url = self.url.join(&format!("/v2/{}/tags/list", self.name))?;
-        let res = self.call(self.get(&url))?;
+        let mut tags = Vec::new();
+        let (page, mut next) = self.get_tags_page(None, None)?;
+        tags.extend(page);
+        while let Some(url) = next {
+            let (page, next_url) = self.get_tags_from(&url)?;
+            if page.is_empty() {
+                break;
+            }
+            tags.extend(page);
+            next = next_url;
+        }
+        Ok(tags)
+    }
+
+    /// Get a page of tags of `<name>` repository.
+    ///
+    /// ```text
+    /// GET /v2/<name>/tags/list?n=<n>&last=<last>
+    /// ```
+    ///
+    /// Returns at most `n` tags lexically after `last`, and the URL of the next page given by `Link` header.
+    pub fn get_tags_page(
+        &mut self,
+        n: Option<usize>,
+        last: Option<&str>,
+    ) -> Result<(Vec<String>, Option<Url>)> {
+        let mut url = self.url.join(&format!("/v2/{}/tags/list", self.name))?;
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(n) = n {
+                query.append_pair("n", &n.to_string());
+            }
+            if let Some(last) = last {
+                query.append_pair("last", last);
+            }
+        }
+        if url.query() == Some("") {
+            url.set_query(None);
+        }
+        self.get_tags_from(&url)
+    }
+
+    fn get_tags_from(&mut self, url: &Url) -> Result<(Vec<String>, Option<Url>)> {
+        let res = self.call(self.get(url))?;
+        let next = res
+            .header("Link")
+            .and_then(parse_next_link)
+            .map(|link| self.url.join(&link))
+            .transpose()?;
         let tag_list = res.into_json::<TagList>()?;
-        Ok(tag_list.tags().to_vec())
+        Ok((tag_list.tags().to_vec(), next))
     }
 
     /// Get manifest for given repository
@@ -103,19 +193,141 @@ impl Client {
     ///
     /// See [corresponding OCI distribution spec document](https://github.com/opencontainers/distribution-spec/blob/main/spec.md#pulling-manifests) for detail.
     pub fn get_manifest(&mut self, reference: &Reference) -> Result<ImageManifest> {
+        Ok(self.get_manifest_with_digest(reference)?.0)
+    }
+
+    /// Get manifest with the digest of its bytes returned from the registry
+    ///
+    /// If `reference` is a digest, the manifest is checked to match it.
+    pub fn get_manifest_with_digest(
+        &mut self,
+        reference: &Reference,
+    ) -> Result<(ImageManifest, Digest)> {
+        let url = self
+            .url
+            .join(&format!("/v2/{}/manifests/{}", self.name, reference))?;
+        let res = self.call(self.get(&url).set("Accept", &manifest_accept()))?;
+        let mut bytes = Vec::new();
+        res.into_reader().read_to_end(&mut bytes)?;
+        let digest = check_manifest_digest(reference, &bytes)?;
+        Ok((ImageManifest::from_reader(bytes.as_slice())?, digest))
+    }
+
+    /// Resolve a reference, usually a tag, to the digest of the manifest
+    ///
+    /// ```text
+    /// HEAD /v2/<name>/manifests/<reference>
+    /// ```
+    ///
+    /// The digest is read from `Docker-Content-Digest` header.
+    /// If the registry does not return it, the manifest is fetched and its digest is evaluated.
+    ///
+    /// See [corresponding OCI distribution spec document](https://github.com/opencontainers/distribution-spec/blob/main/spec.md#checking-if-content-exists-in-the-registry) for detail.
+    pub fn resolve_digest(&mut self, reference: &Reference) -> Result<Digest> {
+        if let Ok(digest) = Digest::from_str(reference) {
+            return Ok(digest);
+        }
+        let url = self
+            .url
+            .join(&format!("/v2/{}/manifests/{}", self.name, reference))?;
+        let res = self.call(self.head(&url).set("Accept", &manifest_accept()))?;
+        if let Some(digest) = res.header("Docker-Content-Digest") {
+            return Ok(Digest::from_str(digest)?);
+        }
+        let res = self.call(self.get(&url).set("Accept", &manifest_accept()))?;
+        let mut bytes = Vec::new();
+        res.into_reader().read_to_end(&mut bytes)?;
+        Ok(Digest::eval_sha256_digest(&bytes))
+    }
+
+    /// Get manifest or index for given repository as it is stored in the registry
+    ///
+    /// ```text
+    /// GET /v2/<name>/manifests/<reference>
+    /// ```
+    ///
+    /// Returns the media type given by `Content-Type` header and the raw bytes,
+    /// which keeps the digest of the manifest unlike [Client::get_manifest].
+    /// If `reference` is a digest, the bytes are checked to match it.
+    pub fn get_raw_manifest(&mut self, reference: &Reference) -> Result<(String, Vec<u8>)> {
+        let url = self
+            .url
+            .join(&format!("/v2/{}/manifests/{}", self.name, reference))?;
+        let res = self.call(self.get(&url).set("Accept", &raw_manifest_accept()))?;
+        let media_type = res
+            .header("Content-Type")
+            .map(|ty| ty.split(';').next().unwrap_or(ty).trim().to_string());
+        let mut bytes = Vec::new();
+        res.into_reader().read_to_end(&mut bytes)?;
+        check_manifest_digest(reference, &bytes)?;
+        let media_type = match media_type {
+            Some(ty) if !ty.is_empty() && ty != "application/json" => ty,
+            // Use `mediaType` field if the registry does not tell the type
+            _ => serde_json::from_slice::<serde_json::Value>(&bytes)?
+                .get("mediaType")
+                .and_then(|ty| ty.as_str())
+                .unwrap_or(MediaType::ImageManifest.as_ref())
+                .to_string(),
+        };
+        Ok((media_type, bytes))
+    }
+
+    /// Push manifest or index as raw bytes, see [Client::get_raw_manifest]
+    ///
+    /// ```text
+    /// PUT /v2/<name>/manifests/<reference>
+    /// ```
+    pub fn push_raw_manifest(
+        &mut self,
+        reference: &Reference,
+        media_type: &str,
+        manifest: &[u8],
+    ) -> Result<()> {
+        let url = self
+            .url
+            .join(&format!("/v2/{}/manifests/{}", self.name, reference))?;
+        let req = self.put(&url).set("Content-Type", media_type);
+        self.send(req, Some(manifest))?.map_err(into_error)?;
+        Ok(())
+    }
+
+    /// Get manifests referring the manifest of `digest` as its `subject`, e.g. signatures or SBOMs
+    ///
+    /// ```text
+    /// GET /v2/<name>/referrers/<digest>
+    /// ```
+    ///
+    /// Returns an empty list if the registry does not support referrers API.
+    ///
+    /// See [corresponding OCI distribution spec document](https://github.com/opencontainers/distribution-spec/blob/main/spec.md#listing-referrers) for detail.
+    pub fn get_referrers(&mut self, digest: &Digest) -> Result<Vec<Descriptor>> {
+        let url = self
+            .url
+            .join(&format!("/v2/{}/referrers/{}", self.name, digest))?;
+        let req = self.get(&url).set("Accept", MediaType::ImageIndex.as_ref());
+        let Some(res) = self.call_optional(req)? else {
+            return Ok(Vec::new());
+        };
+        let index = ImageIndex::from_reader(res.into_reader())?;
+        Ok(index.manifests().clone())
+    }
+
+    /// Same as [Client::resolve_digest], but returns `None` if the manifest does not exist
+    pub fn find_digest(&mut self, reference: &Reference) -> Result<Option<Digest>> {
         let url = self
             .url
             .join(&format!("/v2/{}/manifests/{}", self.name, reference))?;
-        let res = self.call(self.get(&url).set(
-            "Accept",
-            &format!(
-                "{}, {}",
-                MediaType::ImageManifest.to_docker_v2s2().unwrap(),
-                MediaType::ImageManifest,
-            ),
-        ))?;
-        let manifest = ImageManifest::from_reader(res.into_reader())?;
-        Ok(manifest)
+        let req = self.head(&url).set("Accept", &raw_manifest_accept());
+        let Some(res) = self.call_optional(req)? else {
+            return Ok(None);
+        };
+        if let Ok(digest) = Digest::from_str(reference) {
+            return Ok(Some(digest));
+        }
+        match res.header("Docker-Content-Digest") {
+            Some(digest) => Ok(Some(Digest::from_str(digest)?)),
+            None => Ok(Some(self.resolve_digest(reference)?)),
+        }
     }
 
     /// Push manifest to registry
@@ -167,6 +379,20 @@ impl Client {
         Ok(bytes)
     }
 
+    /// Check if the blob of `digest` exists in the repository
+    ///
+    /// ```text
+    /// HEAD /v2/<name>/blobs/<digest>
+    /// ```
+    ///
+    /// See [corresponding OCI distribution spec document](https://github.com/opencontainers/distribution-spec/blob/main/spec.md#checking-if-content-exists-in-the-registry) for detail.
+    pub fn has_blob(&mut self, digest: &Digest) -> Result<bool> {
+        let url = self
+            .url
+            .join(&format!("/v2/{}/blobs/{}", self.name.as_str(), digest))?;
+        Ok(self.call_optional(self.head(&url))?.is_some())
+    }
+
     /// Push blob to registry
     ///
     /// ```text
@@ -211,6 +437,65 @@ impl Client {
     }
 }
 
+/// Parse the URL of `rel="next"` in `Link` header, e.g. `</v2/<name>/tags/list?n=2&last=b>; rel="next"`
+fn parse_next_link(header: &str) -> Option<String> {
+    header.split(',').find_map(|link| {
+        let (url, params) = link.split_once(';')?;
+        params
+            .split(';')
+            .any(|param| matches!(param.trim(), r#"rel="next""# | "rel=next"))
+            .then(|| {
+                url.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+    })
+}
+
+/// Convert an error response into [ErrorResponse] defined in OCI distribution spec if possible
+fn into_error(e: Box<ureq::Error>) -> anyhow::Error {
+    match *e {
+        ureq::Error::Status(status, res) => {
+            let url = res.get_url().to_string();
+            match res.into_json::<ErrorResponse>() {
+                Ok(err) => err.into(),
+                Err(_) => anyhow!("{url}: status code {status}"),
+            }
+        }
+        ureq::Error::Transport(e) => e.into(),
+    }
+}
+
+fn raw_manifest_accept() -> String {
+    format!(
+        "{}, {}, {}, {}",
+        MediaType::ImageManifest.to_docker_v2s2().unwrap(),
+        MediaType::ImageIndex.to_docker_v2s2().unwrap(),
+        MediaType::ImageManifest,
+        MediaType::ImageIndex,
+    )
+}
+
+/// Digest of manifest bytes, which must match `reference` if it is a digest
+fn check_manifest_digest(reference: &Reference, bytes: &[u8]) -> Result<Digest> {
+    let digest = Digest::eval_sha256_digest(bytes);
+    if let Ok(expected) = Digest::from_str(reference) {
+        if digest != expected {
+            bail!("Manifest digest mismatch: expected {expected}, actual {digest}");
+        }
+    }
+    Ok(digest)
+}
+
+fn manifest_accept() -> String {
+    format!(
+        "{}, {}",
+        MediaType::ImageManifest.to_docker_v2s2().unwrap(),
+        MediaType::ImageManifest,
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -227,6 +512,31 @@ mod tests {
         Name::new("test_repo").unwrap()
     }
 
+    #[test]
+    fn next_link() {
+        assert_eq!(
+            parse_next_link(r#"</v2/a/tags/list?n=2&last=b>; rel="next""#).as_deref(),
+            Some("/v2/a/tags/list?n=2&last=b")
+        );
+        assert_eq!(
+            parse_next_link(r#"<https://r.io/prev>; rel="prev", <https://r.io/next>; rel=next"#)
+                .as_deref(),
+            Some("https://r.io/next")
+        );
+        assert_eq!(parse_next_link(r#"</v2/a/tags/list>; rel="prev""#), None);
+    }
+
+    #[test]
+    fn manifest_digest() -> Result<()> {
+        let digest = Digest::eval_sha256_digest(b"manifest");
+        let by_digest = Reference::new(digest.as_ref())?;
+        assert_eq!(check_manifest_digest(&by_digest, b"manifest")?, digest);
+        assert!(check_manifest_digest(&by_digest, b"tampered").is_err());
+        // Manifests fetched by tag cannot be checked
+        assert!(check_manifest_digest(&Reference::new("latest")?, b"tampered").is_ok());
+        Ok(())
+    }
+
     #[test]
     #[ignore]
     fn get_tags() -> Result<()> {
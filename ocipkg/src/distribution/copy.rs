@@ -0,0 +1,434 @@
+use crate::{
+    digest::DigestExt,
+    distribution::{Client, MediaType, StoredAuth},
+    image::{
+        copy, DockerArchive, DockerArchiveBuilder, Image, ImageBuilder, OciArchive,
+        OciArchiveBuilder, OciDir, OciDirBuilder, Remote, RemoteBuilder,
+    },
+    ImageName, Reference,
+};
+use anyhow::{bail, Context, Result};
+use oci_spec::image::{
+    Descriptor, Digest, DigestAlgorithm, ImageIndex, ImageManifest, ToDockerV2S2,
+};
+use std::{collections::HashSet, fmt, path::PathBuf, str::FromStr};
+
+/// Location of an image with a transport prefix like [skopeo](https://github.com/containers/skopeo)
+///
+/// ```
+/// use ocipkg::{distribution::ImageLocation, ImageName};
+/// use std::path::PathBuf;
+///
+/// assert_eq!(
+///     "ghcr.io/termoshtt/ocipkg/static/rust:0.1.0".parse::<ImageLocation>()?,
+///     ImageLocation::Registry(ImageName::parse("ghcr.io/termoshtt/ocipkg/static/rust:0.1.0")?)
+/// );
+/// assert_eq!(
+///     "docker://ubuntu:22.04".parse::<ImageLocation>()?,
+///     ImageLocation::Registry(ImageName::parse("ubuntu:22.04")?)
+/// );
+/// assert_eq!("oci:out".parse::<ImageLocation>()?, ImageLocation::OciDir(PathBuf::from("out")));
+/// assert_eq!(
+///     "oci-archive:out.tar".parse::<ImageLocation>()?,
+///     ImageLocation::OciArchive(PathBuf::from("out.tar"))
+/// );
+/// assert_eq!(
+///     "docker-archive:out.tar".parse::<ImageLocation>()?,
+///     ImageLocation::DockerArchive(PathBuf::from("out.tar"))
+/// );
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageLocation {
+    /// `docker://{image_name}` or `{image_name}`
+    Registry(ImageName),
+    /// `oci:{path}`
+    OciDir(PathBuf),
+    /// `oci-archive:{path}`, which may be compressed
+    OciArchive(PathBuf),
+    /// `docker-archive:{path}`
+    DockerArchive(PathBuf),
+}
+
+impl FromStr for ImageLocation {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("oci:") {
+            return Ok(Self::OciDir(PathBuf::from(path)));
+        }
+        if let Some(path) = s.strip_prefix("oci-archive:") {
+            return Ok(Self::OciArchive(PathBuf::from(path)));
+        }
+        if let Some(path) = s.strip_prefix("docker-archive:") {
+            return Ok(Self::DockerArchive(PathBuf::from(path)));
+        }
+        let name = s.strip_prefix("docker://").unwrap_or(s);
+        Ok(Self::Registry(
+            ImageName::parse(name).with_context(|| format!("Invalid image location: {s}"))?,
+        ))
+    }
+}
+
+impl fmt::Display for ImageLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Registry(name) => write!(f, "docker://{name}"),
+            Self::OciDir(path) => write!(f, "oci:{}", path.display()),
+            Self::OciArchive(path) => write!(f, "oci-archive:{}", path.display()),
+            Self::DockerArchive(path) => write!(f, "docker-archive:{}", path.display()),
+        }
+    }
+}
+
+/// Summary of [CopyImage::run]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CopyReport {
+    /// Number of manifests and indexes written into the destination
+    pub manifests: usize,
+    /// Number of blobs transferred
+    pub blobs_copied: usize,
+    /// Total size of the transferred blobs in bytes
+    pub bytes_copied: u64,
+    /// Number of blobs not transferred since they already exist in the destination
+    pub blobs_skipped: usize,
+}
+
+impl CopyReport {
+    pub fn append(&mut self, other: &CopyReport) {
+        self.manifests += other.manifests;
+        self.blobs_copied += other.blobs_copied;
+        self.bytes_copied += other.bytes_copied;
+        self.blobs_skipped += other.blobs_skipped;
+    }
+}
+
+impl fmt::Display for CopyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} manifests, {} blobs copied ({} bytes), {} blobs skipped",
+            self.manifests, self.blobs_copied, self.bytes_copied, self.blobs_skipped
+        )
+    }
+}
+
+/// Copy an image between registries, oci-dirs, and archives without staging it in local storage
+///
+/// Between registries, manifests are copied as they are, i.e. the digests are kept,
+/// and image indexes, e.g. multi-platform images, are copied with all their manifests.
+/// Otherwise, the image must consist of a single manifest, see [crate::image::copy].
+///
+/// ```no_run
+/// use ocipkg::distribution::CopyImage;
+///
+/// let report = CopyImage::new(
+///     "ghcr.io/termoshtt/ocipkg/static/rust:0.1.0".parse()?,
+///     "localhost:5000/mirror/rust:0.1.0".parse()?,
+/// )
+/// .referrers(true)
+/// .run()?;
+/// println!("{report}");
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct CopyImage {
+    src: ImageLocation,
+    dst: ImageLocation,
+    src_auth: StoredAuth,
+    dst_auth: StoredAuth,
+    referrers: bool,
+}
+
+impl CopyImage {
+    pub fn new(src: ImageLocation, dst: ImageLocation) -> Self {
+        let auth = StoredAuth::load_all().unwrap_or_default();
+        Self {
+            src,
+            dst,
+            src_auth: auth.clone(),
+            dst_auth: auth,
+            referrers: false,
+        }
+    }
+
+    /// Authentication info for the source registry, loaded by [StoredAuth::load_all] if not set
+    pub fn src_auth(mut self, auth: StoredAuth) -> Self {
+        self.src_auth = auth;
+        self
+    }
+
+    /// Authentication info for the destination registry, loaded by [StoredAuth::load_all] if not set
+    pub fn dst_auth(mut self, auth: StoredAuth) -> Self {
+        self.dst_auth = auth;
+        self
+    }
+
+    /// Copy manifests referring the image as `subject`, e.g. signatures or SBOMs. Only supported between registries.
+    pub fn referrers(mut self, referrers: bool) -> Self {
+        self.referrers = referrers;
+        self
+    }
+
+    pub fn run(&self) -> Result<CopyReport> {
+        let mut report = CopyReport::default();
+        if let (ImageLocation::Registry(src), ImageLocation::Registry(dst)) = (&self.src, &self.dst)
+        {
+            let mut src_client = Client::from_image_name_with_auth(src, self.src_auth.clone())?;
+            let mut dst_client = Client::from_image_name_with_auth(dst, self.dst_auth.clone())?;
+            RegistryCopy::new(&mut src_client, &mut dst_client, &mut report)
+                .referrers(self.referrers)
+                .copy_manifest(&src.reference, &dst.reference)?;
+            return Ok(report);
+        }
+        if self.referrers {
+            bail!("Referrers can be copied only between registries");
+        }
+
+        let mut src: Box<dyn Image> = match &self.src {
+            ImageLocation::Registry(name) => {
+                Box::new(Remote::new_with_auth(name.clone(), self.src_auth.clone())?)
+            }
+            ImageLocation::OciDir(path) => Box::new(OciDir::new(path)?),
+            ImageLocation::OciArchive(path) => Box::new(OciArchive::new(path)?),
+            ImageLocation::DockerArchive(path) => Box::new(DockerArchive::new(path)?),
+        };
+        let name = match &self.dst {
+            ImageLocation::Registry(name) => name.clone(),
+            _ => src.get_name()?,
+        };
+        match &self.dst {
+            ImageLocation::Registry(_) => {
+                let builder = RemoteBuilder::new_with_auth(name, self.dst_auth.clone())?;
+                copy(&mut src, Counting::new(builder, &mut report))?;
+            }
+            ImageLocation::OciDir(path) => {
+                let builder = OciDirBuilder::new(path.clone(), name)?;
+                copy(&mut src, Counting::new(builder, &mut report))?;
+            }
+            ImageLocation::OciArchive(path) => {
+                let builder = OciArchiveBuilder::new(path.clone(), name)?;
+                copy(&mut src, Counting::new(builder, &mut report))?;
+            }
+            ImageLocation::DockerArchive(path) => {
+                let builder = DockerArchiveBuilder::new(path.clone(), name)?;
+                copy(&mut src, Counting::new(builder, &mut report))?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// [ImageBuilder] recording transferred blobs into [CopyReport]
+struct Counting<'a, Builder> {
+    builder: Builder,
+    report: &'a mut CopyReport,
+}
+
+impl<'a, Builder: ImageBuilder> Counting<'a, Builder> {
+    fn new(builder: Builder, report: &'a mut CopyReport) -> Self {
+        Self { builder, report }
+    }
+}
+
+impl<Builder: ImageBuilder> ImageBuilder for Counting<'_, Builder> {
+    type Image = Builder::Image;
+
+    fn add_blob(&mut self, data: &[u8]) -> Result<(Digest, u64)> {
+        let (digest, size) = self.builder.add_blob(data)?;
+        self.report.blobs_copied += 1;
+        self.report.bytes_copied += size;
+        Ok((digest, size))
+    }
+
+    fn try_reuse_blob(&mut self, digest: &Digest, size: u64) -> Result<bool> {
+        let reused = self.builder.try_reuse_blob(digest, size)?;
+        if reused {
+            self.report.blobs_skipped += 1;
+        }
+        Ok(reused)
+    }
+
+    fn build(self, manifest: ImageManifest) -> Result<Self::Image> {
+        self.report.manifests += 1;
+        self.builder.build(manifest)
+    }
+}
+
+/// Destination of [RegistryCopy] receiving manifests as raw bytes
+pub(crate) trait RawImageSink {
+    /// Whether the blob of `digest` and `size` already exists
+    fn has_blob(&mut self, digest: &Digest, size: u64) -> Result<bool>;
+
+    fn push_blob(&mut self, blob: &[u8]) -> Result<()>;
+
+    /// `reference` is a tag for the top-level manifest, or its digest for manifests in an index and referrers
+    fn push_manifest(
+        &mut self,
+        reference: &Reference,
+        media_type: &str,
+        manifest: &[u8],
+    ) -> Result<()>;
+
+    /// Digest of the manifest of `reference` if exists
+    fn find_digest(&mut self, reference: &Reference) -> Result<Option<Digest>>;
+}
+
+impl RawImageSink for Client {
+    fn has_blob(&mut self, digest: &Digest, _size: u64) -> Result<bool> {
+        Client::has_blob(self, digest)
+    }
+
+    fn push_blob(&mut self, blob: &[u8]) -> Result<()> {
+        Client::push_blob(self, blob)?;
+        Ok(())
+    }
+
+    fn push_manifest(
+        &mut self,
+        reference: &Reference,
+        media_type: &str,
+        manifest: &[u8],
+    ) -> Result<()> {
+        self.push_raw_manifest(reference, media_type, manifest)
+    }
+
+    fn find_digest(&mut self, reference: &Reference) -> Result<Option<Digest>> {
+        Client::find_digest(self, reference)
+    }
+}
+
+/// Copy manifests as raw bytes from a registry
+pub(crate) struct RegistryCopy<'a> {
+    src: &'a mut Client,
+    dst: &'a mut dyn RawImageSink,
+    referrers: bool,
+    // Manifests already copied, to avoid copying referrers twice
+    done: HashSet<Digest>,
+    report: &'a mut CopyReport,
+}
+
+impl<'a> RegistryCopy<'a> {
+    pub(crate) fn new(
+        src: &'a mut Client,
+        dst: &'a mut dyn RawImageSink,
+        report: &'a mut CopyReport,
+    ) -> Self {
+        Self {
+            src,
+            dst,
+            referrers: false,
+            done: HashSet::new(),
+            report,
+        }
+    }
+
+    pub(crate) fn referrers(mut self, referrers: bool) -> Self {
+        self.referrers = referrers;
+        self
+    }
+
+    pub(crate) fn copy_manifest(&mut self, src: &Reference, dst: &Reference) -> Result<()> {
+        let (media_type, manifest) = self.src.get_raw_manifest(src)?;
+        let digest = Digest::eval_sha256_digest(&manifest);
+        if is_index(&media_type) {
+            let index: ImageIndex = serde_json::from_slice(&manifest)?;
+            for desc in index.manifests() {
+                let reference = Reference::new(desc.digest().as_ref())?;
+                self.copy_manifest(&reference, &reference)?;
+            }
+        } else {
+            let image: ImageManifest = serde_json::from_slice(&manifest)?;
+            for desc in image.layers().iter().chain([image.config()]) {
+                self.copy_blob(desc)?;
+            }
+        }
+        self.dst.push_manifest(dst, &media_type, &manifest)?;
+        self.report.manifests += 1;
+        self.done.insert(digest.clone());
+
+        if self.referrers {
+            for desc in self.src.get_referrers(&digest)? {
+                if self.done.contains(desc.digest()) {
+                    continue;
+                }
+                let reference = Reference::new(desc.digest().as_ref())?;
+                self.copy_manifest(&reference, &reference)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_blob(&mut self, desc: &Descriptor) -> Result<()> {
+        let digest = desc.digest();
+        if self.dst.has_blob(digest, desc.size())? {
+            log::info!("Skip existing blob: {digest}");
+            self.report.blobs_skipped += 1;
+            return Ok(());
+        }
+        let blob = self.src.get_blob(digest)?;
+        if digest.algorithm() == &DigestAlgorithm::Sha256
+            && &Digest::eval_sha256_digest(&blob) != digest
+        {
+            bail!("Digest of blob mismatch: {digest}");
+        }
+        self.dst.push_blob(&blob)?;
+        self.report.blobs_copied += 1;
+        self.report.bytes_copied += blob.len() as u64;
+        Ok(())
+    }
+}
+
+fn is_index(media_type: &str) -> bool {
+    media_type == MediaType::ImageIndex.as_ref()
+        || MediaType::ImageIndex
+            .to_docker_v2s2()
+            .is_ok_and(|docker| media_type == docker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Builder;
+    use std::fs;
+
+    #[test]
+    fn copy_between_files() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let input = tmp_dir.path().join("input.txt");
+        fs::write(&input, "hello")?;
+        let name = ImageName::parse("localhost:5000/test/copy:1")?;
+        let src = tmp_dir.path().join("src.tar");
+        let mut b = Builder::new(src.clone(), name.clone())?;
+        b.append_files(&[&input])?;
+        let manifest = b.build()?.get_manifest()?;
+
+        let oci_dir = tmp_dir.path().join("oci-dir");
+        let report = CopyImage::new(
+            ImageLocation::OciArchive(src),
+            format!("oci:{}", oci_dir.display()).parse()?,
+        )
+        .run()?;
+        assert_eq!(report.manifests, 1);
+        assert_eq!(report.blobs_copied, manifest.layers().len() + 1);
+        assert_eq!(report.blobs_skipped, 0);
+
+        let docker = tmp_dir.path().join("docker.tar");
+        CopyImage::new(
+            ImageLocation::OciDir(oci_dir),
+            ImageLocation::DockerArchive(docker.clone()),
+        )
+        .run()?;
+        let mut docker = DockerArchive::new(&docker)?;
+        assert_eq!(docker.get_name()?, name);
+        assert_eq!(docker.get_manifest()?, manifest);
+
+        assert!(CopyImage::new(
+            ImageLocation::OciArchive(tmp_dir.path().join("src.tar")),
+            ImageLocation::OciArchive(tmp_dir.path().join("dst.tar")),
+        )
+        .referrers(true)
+        .run()
+        .is_err());
+        Ok(())
+    }
+}
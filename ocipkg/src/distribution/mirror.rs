@@ -0,0 +1,472 @@
+use crate::{
+    digest::DigestExt,
+    distribution::{Client, CopyReport, RawImageSink, RegistryCopy, StoredAuth},
+    image::reproducible::to_canonical_json,
+    image_name_req::parse_tag,
+    ImageName, Reference,
+};
+use anyhow::{bail, Context, Result};
+use oci_spec::image::{DescriptorBuilder, Digest, ImageIndex, ImageIndexBuilder, MediaType};
+use regex::Regex;
+use semver::VersionReq;
+use std::{collections::HashMap, fmt, fs, io::Write, path::PathBuf, str::FromStr};
+
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// Destination of [Mirror]
+///
+/// ```
+/// use ocipkg::distribution::MirrorDestination;
+/// use std::path::PathBuf;
+///
+/// assert_eq!(
+///     "docker://localhost:5000/backup".parse::<MirrorDestination>()?,
+///     MirrorDestination::Registry("localhost:5000/backup".to_string())
+/// );
+/// assert_eq!(
+///     "oci:backup".parse::<MirrorDestination>()?,
+///     MirrorDestination::OciDir(PathBuf::from("backup"))
+/// );
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MirrorDestination {
+    /// `[docker://]{registry}[/{prefix}]`. A repository `{hostname}/{name}` is mirrored into `{registry}/{prefix}/{name}`.
+    Registry(String),
+    /// `oci:{path}`. All tags are stored in a single oci-dir with multiple manifests,
+    /// distinguished by `org.opencontainers.image.ref.name` annotation.
+    OciDir(PathBuf),
+}
+
+impl FromStr for MirrorDestination {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("oci:") {
+            return Ok(Self::OciDir(PathBuf::from(path)));
+        }
+        let prefix = s
+            .strip_prefix("docker://")
+            .unwrap_or(s)
+            .trim_end_matches('/');
+        // Validate the registry and prefix by composing a image name
+        ImageName::parse(&format!("{prefix}/mirror:latest"))
+            .with_context(|| format!("Invalid mirror destination: {s}"))?;
+        Ok(Self::Registry(prefix.to_string()))
+    }
+}
+
+impl fmt::Display for MirrorDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Registry(prefix) => write!(f, "docker://{prefix}"),
+            Self::OciDir(path) => write!(f, "oci:{}", path.display()),
+        }
+    }
+}
+
+/// Summary of [Mirror::run]
+#[derive(Debug, Clone, Default)]
+pub struct MirrorReport {
+    /// Tags newly copied into the destination
+    pub copied: Vec<ImageName>,
+    /// Tags copied again since their digests have been changed, only in sync mode
+    pub updated: Vec<ImageName>,
+    /// Tags already in the destination. In sync mode, their digests are also the same.
+    pub skipped: Vec<ImageName>,
+    /// Tags failed to be copied with the reason
+    pub failed: Vec<(ImageName, String)>,
+    /// Summary of transferred manifests and blobs
+    pub transfer: CopyReport,
+}
+
+impl fmt::Display for MirrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} copied, {} updated, {} skipped, {} failed",
+            self.copied.len(),
+            self.updated.len(),
+            self.skipped.len(),
+            self.failed.len()
+        )?;
+        write!(f, "Transferred: {}", self.transfer)?;
+        for (image_name, reason) in &self.failed {
+            write!(f, "\nFailed {image_name}: {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Mirror all tags of repositories into a registry or an oci-dir
+///
+/// Tags are listed by [Client::get_tags], and manifests are copied as they are,
+/// i.e. the digests are kept, see [crate::distribution::CopyImage].
+/// Tags already in the destination are not copied, unless [Mirror::sync] is enabled and their digests differ.
+///
+/// ```no_run
+/// use ocipkg::{distribution::Mirror, ImageName};
+///
+/// let report = Mirror::new("localhost:5000/backup".parse()?)
+///     .repository(ImageName::parse("ghcr.io/termoshtt/ocipkg/static/rust")?)
+///     .version_req("^0.1".parse()?)
+///     .sync(true)
+///     .run()?;
+/// println!("{report}");
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    dst: MirrorDestination,
+    repositories: Vec<ImageName>,
+    tag_regex: Option<Regex>,
+    version_req: Option<VersionReq>,
+    sync: bool,
+    src_auth: StoredAuth,
+    dst_auth: StoredAuth,
+}
+
+impl Mirror {
+    pub fn new(dst: MirrorDestination) -> Self {
+        let auth = StoredAuth::load_all().unwrap_or_default();
+        Self {
+            dst,
+            repositories: Vec::new(),
+            tag_regex: None,
+            version_req: None,
+            sync: false,
+            src_auth: auth.clone(),
+            dst_auth: auth,
+        }
+    }
+
+    /// Add a repository to be mirrored. The reference of `image_name` is ignored.
+    pub fn repository(mut self, image_name: ImageName) -> Self {
+        self.repositories.push(image_name);
+        self
+    }
+
+    /// Mirror only tags matching the regular expression, e.g. `^v1\.`
+    pub fn tag_regex(mut self, regex: Regex) -> Self {
+        self.tag_regex = Some(regex);
+        self
+    }
+
+    /// Mirror only tags which are [semver] versions satisfying the requirement, e.g. `>=1.2`
+    ///
+    /// Tags are parsed with an optional `v` prefix as [crate::ImageNameReq] does.
+    pub fn version_req(mut self, req: VersionReq) -> Self {
+        self.version_req = Some(req);
+        self
+    }
+
+    /// Copy tags whose digests in the destination differ from the source
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Authentication info for the source registries, loaded by [StoredAuth::load_all] if not set
+    pub fn src_auth(mut self, auth: StoredAuth) -> Self {
+        self.src_auth = auth;
+        self
+    }
+
+    /// Authentication info for the destination registry, loaded by [StoredAuth::load_all] if not set
+    pub fn dst_auth(mut self, auth: StoredAuth) -> Self {
+        self.dst_auth = auth;
+        self
+    }
+
+    /// Mirror tags of the repositories
+    ///
+    /// Failures of each tag are recorded in [MirrorReport::failed] and do not stop mirroring other tags.
+    /// This fails if tags of a repository cannot be listed.
+    pub fn run(&self) -> Result<MirrorReport> {
+        let mut report = MirrorReport::default();
+        for repository in &self.repositories {
+            let mut src = Client::from_image_name_with_auth(repository, self.src_auth.clone())?;
+            let tags = src.get_tags().with_context(|| {
+                format!("Failed to list tags of {}", repository_name(repository))
+            })?;
+            let tags = self.select_tags(tags);
+            log::info!(
+                "Mirror {} tags of {}",
+                tags.len(),
+                repository_name(repository)
+            );
+
+            let mut dst: Box<dyn RawImageSink> = match &self.dst {
+                MirrorDestination::Registry(prefix) => {
+                    let image_name =
+                        ImageName::parse(&format!("{prefix}/{}:latest", repository.name))?;
+                    Box::new(Client::from_image_name_with_auth(
+                        &image_name,
+                        self.dst_auth.clone(),
+                    )?)
+                }
+                MirrorDestination::OciDir(path) => {
+                    Box::new(OciDirSink::open(path.clone(), repository.clone())?)
+                }
+            };
+            for tag in tags {
+                let reference = Reference::new(&tag)?;
+                let image_name = ImageName {
+                    reference: reference.clone(),
+                    ..repository.clone()
+                };
+                match self.mirror_tag(&mut src, dst.as_mut(), &reference, &mut report.transfer) {
+                    Ok(Outcome::Copied) => report.copied.push(image_name),
+                    Ok(Outcome::Updated) => report.updated.push(image_name),
+                    Ok(Outcome::Skipped) => report.skipped.push(image_name),
+                    Err(e) => {
+                        log::error!("Failed to mirror {image_name}: {e:#}");
+                        report.failed.push((image_name, format!("{e:#}")));
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    fn mirror_tag(
+        &self,
+        src: &mut Client,
+        dst: &mut dyn RawImageSink,
+        reference: &Reference,
+        transfer: &mut CopyReport,
+    ) -> Result<Outcome> {
+        let outcome = match dst.find_digest(reference)? {
+            None => Outcome::Copied,
+            Some(_) if !self.sync => return Ok(Outcome::Skipped),
+            Some(digest) => {
+                if src.find_digest(reference)?.as_ref() == Some(&digest) {
+                    return Ok(Outcome::Skipped);
+                }
+                Outcome::Updated
+            }
+        };
+        RegistryCopy::new(src, dst, transfer).copy_manifest(reference, reference)?;
+        Ok(outcome)
+    }
+
+    /// Filter tags by the regular expression and version requirement, and sort them
+    fn select_tags(&self, tags: Vec<String>) -> Vec<String> {
+        let mut tags: Vec<String> = tags
+            .into_iter()
+            .filter(|tag| self.tag_regex.as_ref().is_none_or(|re| re.is_match(tag)))
+            .filter(|tag| {
+                self.version_req
+                    .as_ref()
+                    .is_none_or(|req| parse_tag(tag).is_some_and(|version| req.matches(&version)))
+            })
+            .collect();
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+}
+
+enum Outcome {
+    Copied,
+    Updated,
+    Skipped,
+}
+
+/// `{hostname}/{name}` without reference
+fn repository_name(image_name: &ImageName) -> String {
+    match image_name.port {
+        Some(port) => format!("{}:{}/{}", image_name.hostname, port, image_name.name),
+        None => format!("{}/{}", image_name.hostname, image_name.name),
+    }
+}
+
+/// oci-dir storing multiple manifests, each named by `org.opencontainers.image.ref.name` annotation
+struct OciDirSink {
+    root: PathBuf,
+    index: ImageIndex,
+    /// Repository of the manifests pushed by tag
+    repository: ImageName,
+}
+
+impl OciDirSink {
+    fn open(root: PathBuf, repository: ImageName) -> Result<Self> {
+        let index_path = root.join("index.json");
+        let index = if index_path.exists() {
+            ImageIndex::from_file(&index_path)?
+        } else {
+            if root.exists() && fs::read_dir(&root)?.next().is_some() {
+                bail!("{} is not empty, and is not a oci-dir", root.display());
+            }
+            fs::create_dir_all(&root)?;
+            fs::write(root.join("oci-layout"), r#"{"imageLayoutVersion":"1.0.0"}"#)?;
+            ImageIndexBuilder::default()
+                .schema_version(2_u32)
+                .manifests(Vec::new())
+                .build()?
+        };
+        Ok(Self {
+            root,
+            index,
+            repository,
+        })
+    }
+
+    fn ref_name(&self, reference: &Reference) -> String {
+        ImageName {
+            reference: reference.clone(),
+            ..self.repository.clone()
+        }
+        .to_string()
+    }
+}
+
+impl RawImageSink for OciDirSink {
+    fn has_blob(&mut self, digest: &Digest, size: u64) -> Result<bool> {
+        Ok(fs::metadata(self.root.join(digest.as_path()))
+            .is_ok_and(|meta| meta.is_file() && meta.len() == size))
+    }
+
+    fn push_blob(&mut self, blob: &[u8]) -> Result<()> {
+        let path = self.root.join(Digest::eval_sha256_digest(blob).as_path());
+        let parent = path.parent().unwrap();
+        fs::create_dir_all(parent)?;
+        // Not to leave a truncated blob which is regarded as existing in the next run
+        let mut tmp = tempfile::NamedTempFile::new_in(parent)?;
+        tmp.write_all(blob)?;
+        tmp.persist(path)?;
+        Ok(())
+    }
+
+    fn push_manifest(
+        &mut self,
+        reference: &Reference,
+        media_type: &str,
+        manifest: &[u8],
+    ) -> Result<()> {
+        self.push_blob(manifest)?;
+        if Digest::from_str(reference).is_ok() {
+            // Manifests in an index or referrers are stored only as blobs
+            return Ok(());
+        }
+        let name = self.ref_name(reference);
+        let descriptor = DescriptorBuilder::default()
+            .media_type(MediaType::from(media_type))
+            .digest(Digest::eval_sha256_digest(manifest))
+            .size(manifest.len() as u64)
+            .annotations(HashMap::from([(REF_NAME.to_string(), name.clone())]))
+            .build()?;
+        let mut manifests = self.index.manifests().clone();
+        manifests.retain(|desc| {
+            desc.annotations()
+                .as_ref()
+                .and_then(|annotations| annotations.get(REF_NAME))
+                != Some(&name)
+        });
+        manifests.push(descriptor);
+        self.index.set_manifests(manifests);
+        // Update index.json for each tag, and replace it atomically
+        // to keep the mirror consistent even if interrupted
+        let mut tmp = tempfile::NamedTempFile::new_in(&self.root)?;
+        tmp.write_all(to_canonical_json(&self.index)?.as_bytes())?;
+        tmp.persist(self.root.join("index.json"))?;
+        Ok(())
+    }
+
+    fn find_digest(&mut self, reference: &Reference) -> Result<Option<Digest>> {
+        let name = self.ref_name(reference);
+        Ok(self
+            .index
+            .manifests()
+            .iter()
+            .find(|desc| {
+                desc.annotations()
+                    .as_ref()
+                    .and_then(|annotations| annotations.get(REF_NAME))
+                    == Some(&name)
+            })
+            .map(|desc| desc.digest().clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_tags() -> Result<()> {
+        let tags = [
+            "latest",
+            "v1.0.0",
+            "1.2.0",
+            "2.0.0",
+            "1.2.0-rc1",
+            "nightly-1",
+        ]
+        .map(String::from)
+        .to_vec();
+        let mirror = Mirror::new("oci:out".parse()?);
+        assert_eq!(mirror.select_tags(tags.clone()).len(), tags.len());
+        assert_eq!(
+            mirror
+                .clone()
+                .version_req("^1".parse()?)
+                .select_tags(tags.clone()),
+            ["1.2.0", "v1.0.0"]
+        );
+        assert_eq!(
+            mirror
+                .clone()
+                .tag_regex(Regex::new("^nightly-")?)
+                .select_tags(tags.clone()),
+            ["nightly-1"]
+        );
+        assert_eq!(
+            mirror
+                .tag_regex(Regex::new(r"^\d")?)
+                .version_req(">=1.2.0-rc1".parse()?)
+                .select_tags(tags),
+            ["1.2.0", "1.2.0-rc1", "2.0.0"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn oci_dir_sink() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let root = tmp_dir.path().join("mirror");
+        let repository = ImageName::parse("ghcr.io/termoshtt/test:latest")?;
+        let tag = Reference::new("1.0.0")?;
+        let manifest_type = MediaType::ImageManifest.to_string();
+
+        let mut sink = OciDirSink::open(root.clone(), repository.clone())?;
+        assert_eq!(sink.find_digest(&tag)?, None);
+        sink.push_blob(b"layer")?;
+        let layer = Digest::eval_sha256_digest(b"layer");
+        assert!(sink.has_blob(&layer, 5)?);
+        // Truncated blob left by an interrupted run is not regarded as existing
+        fs::write(root.join(layer.as_path()), b"lay")?;
+        assert!(!sink.has_blob(&layer, 5)?);
+        sink.push_manifest(&tag, &manifest_type, b"manifest1")?;
+        sink.push_manifest(&Reference::new("2.0.0")?, &manifest_type, b"manifest2")?;
+
+        // Reopen, and replace the manifest of the same tag
+        let mut sink = OciDirSink::open(root.clone(), repository)?;
+        assert_eq!(
+            sink.find_digest(&tag)?,
+            Some(Digest::eval_sha256_digest(b"manifest1"))
+        );
+        sink.push_manifest(&tag, &manifest_type, b"manifest3")?;
+        assert_eq!(
+            sink.find_digest(&tag)?,
+            Some(Digest::eval_sha256_digest(b"manifest3"))
+        );
+        let index = ImageIndex::from_file(root.join("index.json"))?;
+        assert_eq!(index.manifests().len(), 2);
+        assert!(root.join("oci-layout").is_file());
+
+        // Another repository in the same oci-dir
+        let mut sink = OciDirSink::open(root, ImageName::parse("ghcr.io/termoshtt/other:latest")?)?;
+        assert_eq!(sink.find_digest(&tag)?, None);
+        Ok(())
+    }
+}
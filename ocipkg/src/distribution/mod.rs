@@ -1,30 +1,80 @@
 //! Pull and Push images to OCI registry based on [OCI distribution specification](https://github.com/opencontainers/distribution-spec)
 
 use crate::{
-    image::{copy, Artifact, Image, OciArchive, RemoteBuilder},
-    ImageName,
+    image::{copy, read_archive, Artifact, Image, RemoteBuilder},
+    local, ImageName, ImageNameReq,
 };
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use std::path::Path;
 
 mod auth;
 mod client;
+mod copy;
+mod mirror;
 
 pub use auth::*;
 pub use client::Client;
+pub use copy::*;
+pub use mirror::*;
 pub use oci_spec::image::MediaType;
 
 /// Push image to registry
 pub fn push_image(path: &Path) -> Result<()> {
-    let mut oci_archive = OciArchive::new(path)?;
-    let image_name = oci_archive.get_name()?;
+    let mut archive = read_archive(path)?;
+    let image_name = archive.get_name()?;
     let remote = RemoteBuilder::new(image_name)?;
-    copy(&mut oci_archive, remote)?;
+    copy(&mut archive, remote)?;
     Ok(())
 }
 
+/// Resolve a version requirement into the image of the highest matching tag in registry
+///
+/// The resolution is recorded into local storage, see [local::load_resolution].
+pub fn resolve_version(req: &ImageNameReq) -> Result<ImageName> {
+    resolve_version_with_auth(req, StoredAuth::load_all().unwrap_or_default())
+}
+
+/// [resolve_version] with the given authentication info
+pub fn resolve_version_with_auth(req: &ImageNameReq, auth: StoredAuth) -> Result<ImageName> {
+    let mut client = Client::new_with_auth(
+        req.with_tag("latest")?.registry_url()?,
+        req.name.clone(),
+        auth,
+    )?;
+    let tags = client.get_tags()?;
+    let tag = req
+        .select(tags.iter().map(String::as_str))
+        .with_context(|| format!("No tag matching {req} found in registry"))?;
+    let image_name = req.with_tag(tag)?;
+    log::info!("Resolved {req} to {image_name}");
+    local::save_resolution(req, &image_name)?;
+    Ok(image_name)
+}
+
+/// Parse an image name, resolving a version requirement by [resolve_version] if it is given as a reference
+pub fn resolve_image_name(name: &str) -> Result<ImageName> {
+    match ImageNameReq::parse(name)? {
+        Some(req) => resolve_version(&req),
+        None => ImageName::parse(name),
+    }
+}
+
 /// Get image from registry and save it into local storage
+///
+/// In offline mode, this succeeds without fetching if the image is already stored and `overwrite` is not set.
 pub fn get_image(image_name: &ImageName, overwrite: bool) -> Result<()> {
+    if local::is_offline() {
+        if !local::image_dir(image_name)?.exists() {
+            local::ensure_online(image_name, true)?;
+        }
+        if overwrite {
+            bail!(
+                "{image_name} cannot be re-fetched in offline mode ({})",
+                local::OFFLINE_ENV
+            );
+        }
+        return Ok(());
+    }
     let mut artifact = Artifact::from_remote(image_name.clone())?;
     artifact.unpack(overwrite)?;
     Ok(())
@@ -0,0 +1,276 @@
+//! Encryption of layer blobs for confidential artifacts
+//!
+//! Layers are encrypted in a similar way to [ocicrypt] and [age]:
+//!
+//! - Each layer is encrypted by ChaCha20-Poly1305 with a random data key,
+//!   and the media type of the layer gets `+encrypted` suffix,
+//!   e.g. `application/vnd.ocipkg.v1.layer.tar+gzip+encrypted`.
+//! - The data key is wrapped for each recipient using X25519 key agreement with an ephemeral key and HKDF-SHA256,
+//!   and stored in the [ENC_KEYS_ANNOTATION] annotation of the layer descriptor.
+//!
+//! A recipient decrypts the layer with its [PrivateKey], which is loaded from the path
+//! specified by `OCIPKG_PRIVATE_KEY` environment variable or [PrivateKey::default_path].
+//!
+//! [ocicrypt]: https://github.com/containers/ocicrypt
+//! [age]: https://age-encryption.org/
+
+use anyhow::{bail, Context, Result};
+use base64::engine::{general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Annotation key of layer descriptor storing wrapped data keys for recipients
+pub const ENC_KEYS_ANNOTATION: &str = "vnd.ocipkg.enc.keys.x25519";
+
+/// Environment variable to specify the path of private key file
+pub const PRIVATE_KEY_ENV: &str = "OCIPKG_PRIVATE_KEY";
+
+const PUBLIC_KEY_PREFIX: &str = "ocipkg-pub:";
+const PRIVATE_KEY_PREFIX: &str = "ocipkg-key:";
+const HKDF_INFO: &[u8] = b"ocipkg-layer-key-v1";
+const NONCE_SIZE: usize = 12;
+
+/// Public key of a recipient, encoded as `ocipkg-pub:{base64}`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey(x25519_dalek::PublicKey);
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            PUBLIC_KEY_PREFIX,
+            STANDARD.encode(self.0.as_bytes())
+        )
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let encoded = s
+            .trim()
+            .strip_prefix(PUBLIC_KEY_PREFIX)
+            .with_context(|| format!("Public key must start with `{PUBLIC_KEY_PREFIX}`"))?;
+        Ok(Self(decode_key(encoded)?.into()))
+    }
+}
+
+/// Private key of a recipient, encoded as `ocipkg-key:{base64}`
+#[derive(Clone)]
+pub struct PrivateKey(x25519_dalek::StaticSecret);
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print secret
+        write!(f, "PrivateKey({})", self.public_key())
+    }
+}
+
+impl FromStr for PrivateKey {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let encoded = s
+            .trim()
+            .strip_prefix(PRIVATE_KEY_PREFIX)
+            .with_context(|| format!("Private key must start with `{PRIVATE_KEY_PREFIX}`"))?;
+        Ok(Self(decode_key(encoded)?.into()))
+    }
+}
+
+impl PrivateKey {
+    /// Generate a new private key
+    pub fn generate() -> Self {
+        Self(x25519_dalek::StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey((&self.0).into())
+    }
+
+    /// Encode into `ocipkg-key:{base64}` form
+    pub fn to_encoded(&self) -> String {
+        format!(
+            "{}{}",
+            PRIVATE_KEY_PREFIX,
+            STANDARD.encode(self.0.as_bytes())
+        )
+    }
+
+    /// Default path of private key file, `$XDG_CONFIG_HOME/ocipkg/key.txt` on Linux
+    pub fn default_path() -> Result<PathBuf> {
+        let dirs = directories::ProjectDirs::from("", "", "ocipkg")
+            .context("Cannot get project directory of ocipkg")?;
+        Ok(dirs.config_dir().join("key.txt"))
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read private key: {}", path.display()))?;
+        content.parse()
+    }
+
+    /// Load private key from `OCIPKG_PRIVATE_KEY` or [PrivateKey::default_path]
+    pub fn load() -> Result<Self> {
+        let path = match std::env::var_os(PRIVATE_KEY_ENV) {
+            Some(path) => PathBuf::from(path),
+            None => Self::default_path()?,
+        };
+        Self::from_path(&path)
+    }
+
+    /// Save private key into a file. This fails if the file already exists.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if path.exists() {
+            bail!("File already exists: {}", path.display());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut opts = fs::OpenOptions::new();
+        opts.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            opts.mode(0o600);
+        }
+        use std::io::Write;
+        writeln!(opts.open(path)?, "{}", self.to_encoded())?;
+        Ok(())
+    }
+}
+
+fn decode_key(encoded: &str) -> Result<[u8; 32]> {
+    let bytes = STANDARD.decode(encoded)?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Key must be 32 bytes"))
+}
+
+/// Data key wrapped for a recipient
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Stanza {
+    /// Ephemeral public key, base64 encoded
+    epk: String,
+    /// Wrapped data key, base64 encoded
+    key: String,
+}
+
+fn wrapping_key(
+    shared: &x25519_dalek::SharedSecret,
+    epk: &x25519_dalek::PublicKey,
+    recipient: &x25519_dalek::PublicKey,
+) -> Key {
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(epk.as_bytes());
+    salt.extend_from_slice(recipient.as_bytes());
+    let hk = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+    let mut okm = [0u8; 32];
+    hk.expand(HKDF_INFO, &mut okm)
+        .expect("32 bytes is valid length for HKDF-SHA256");
+    okm.into()
+}
+
+/// Encrypt a blob for recipients
+///
+/// Returns the encrypted blob and the value of [ENC_KEYS_ANNOTATION] annotation.
+pub fn encrypt(blob: &[u8], recipients: &[PublicKey]) -> Result<(Vec<u8>, String)> {
+    if recipients.is_empty() {
+        bail!("No recipient is specified for encryption");
+    }
+    let data_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let cipher = ChaCha20Poly1305::new(&data_key);
+    let ciphertext = cipher
+        .encrypt(&nonce, blob)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt layer"))?;
+    let mut out = nonce.to_vec();
+    out.extend(ciphertext);
+
+    let mut stanzas = Vec::new();
+    for recipient in recipients {
+        let esk = x25519_dalek::EphemeralSecret::random_from_rng(OsRng);
+        let epk = x25519_dalek::PublicKey::from(&esk);
+        let shared = esk.diffie_hellman(&recipient.0);
+        let wrap = ChaCha20Poly1305::new(&wrapping_key(&shared, &epk, &recipient.0));
+        // Wrapping key is used only once, thus zero nonce is safe.
+        let wrapped = wrap
+            .encrypt(&Nonce::default(), data_key.as_slice())
+            .map_err(|_| anyhow::anyhow!("Failed to wrap data key"))?;
+        stanzas.push(Stanza {
+            epk: STANDARD.encode(epk.as_bytes()),
+            key: STANDARD.encode(wrapped),
+        });
+    }
+    Ok((out, serde_json::to_string(&stanzas)?))
+}
+
+/// Decrypt a blob encrypted by [encrypt] using the value of [ENC_KEYS_ANNOTATION] annotation
+pub fn decrypt(blob: &[u8], keys: &str, private_key: &PrivateKey) -> Result<Vec<u8>> {
+    let stanzas: Vec<Stanza> = serde_json::from_str(keys)?;
+    let recipient = private_key.public_key().0;
+    let data_key = stanzas
+        .iter()
+        .find_map(|stanza| {
+            let epk = x25519_dalek::PublicKey::from(decode_key(&stanza.epk).ok()?);
+            let wrapped = STANDARD.decode(&stanza.key).ok()?;
+            let shared = private_key.0.diffie_hellman(&epk);
+            let wrap = ChaCha20Poly1305::new(&wrapping_key(&shared, &epk, &recipient));
+            wrap.decrypt(&Nonce::default(), wrapped.as_slice()).ok()
+        })
+        .context("The private key is not a recipient of this layer")?;
+
+    if blob.len() < NONCE_SIZE {
+        bail!("Encrypted layer is too short");
+    }
+    let (nonce, ciphertext) = blob.split_at(NONCE_SIZE);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&data_key));
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow::anyhow!("Failed to decrypt layer, it may be corrupted"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let alice = PrivateKey::generate();
+        let bob = PrivateKey::generate();
+        let eve = PrivateKey::generate();
+        let (encrypted, keys) = encrypt(b"secret", &[alice.public_key(), bob.public_key()])?;
+        assert_ne!(encrypted, b"secret");
+        assert_eq!(decrypt(&encrypted, &keys, &alice)?, b"secret");
+        assert_eq!(decrypt(&encrypted, &keys, &bob)?, b"secret");
+        assert!(decrypt(&encrypted, &keys, &eve).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn key_encoding() -> Result<()> {
+        let key = PrivateKey::generate();
+        let decoded: PrivateKey = key.to_encoded().parse()?;
+        assert_eq!(decoded.public_key(), key.public_key());
+        let public: PublicKey = key.public_key().to_string().parse()?;
+        assert_eq!(public, key.public_key());
+        assert!("ocipkg-pub:AAAA".parse::<PublicKey>().is_err());
+        Ok(())
+    }
+}
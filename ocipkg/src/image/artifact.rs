@@ -1,11 +1,16 @@
 //! Compose directory as a container tar
 
 use crate::{
+    encryption::{self, PrivateKey, PublicKey, ENC_KEYS_ANNOTATION},
     image::{
-        copy, Config, Image, OciArchive, OciArchiveBuilder, OciArtifact, OciArtifactBuilder,
-        OciDir, OciDirBuilder,
+        compression::layer_reader,
+        copy, read_archive,
+        reproducible::append_path,
+        unpack::{verify_unpacked, Unpacker, DEFAULT_MAX_UNPACK_SIZE},
+        Compression, Config, Image, LinkManifest, OciArchive, OciArchiveBuilder, OciArtifact,
+        OciArtifactBuilder, OciDir, OciDirBuilder, CONFIG_VERSION,
     },
-    local::image_dir,
+    local::{self, image_dir},
     media_types::{self, config_json},
     ImageName,
 };
@@ -13,11 +18,10 @@ use crate::{
 #[cfg(feature = "remote")]
 use crate::image::Remote;
 use anyhow::{bail, Context, Result};
-use flate2::{write::GzEncoder, Compression};
-use oci_spec::image::MediaType;
+use oci_spec::image::{Descriptor, MediaType};
 use std::{
     collections::HashMap,
-    fs,
+    fs, io,
     ops::{Deref, DerefMut},
     path::{Path, PathBuf},
 };
@@ -25,6 +29,8 @@ use std::{
 /// Build [Artifact]
 pub struct Builder {
     config: Config,
+    recipients: Vec<PublicKey>,
+    compression: Compression,
     builder: OciArtifactBuilder<OciArchiveBuilder>,
 }
 
@@ -36,13 +42,43 @@ impl Builder {
                 media_types::artifact(),
             )?,
             config: Config::default(),
+            recipients: Vec::new(),
+            compression: Compression::default(),
         })
     }
 
+    /// Set compression algorithm and level of layers appended after this call
+    ///
+    /// gzip with the default level is used if not set.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Encrypt layers for the recipient
+    ///
+    /// Layers appended after this call are encrypted if at least one recipient is added.
+    /// The config blob is not encrypted, i.e. the list of files is visible to anyone.
+    pub fn add_recipient(&mut self, recipient: PublicKey) {
+        self.recipients.push(recipient);
+    }
+
+    /// Compress the tar archive, and add it as a layer
+    fn add_layer(&mut self, tar: &[u8]) -> Result<Descriptor> {
+        let media_type = self.compression.layer_media_type();
+        let buf = self.compression.compress(tar)?;
+        if self.recipients.is_empty() {
+            self.builder.add_layer(media_type, &buf, HashMap::new())
+        } else {
+            self.builder
+                .add_encrypted_layer(media_type, &buf, &self.recipients, HashMap::new())
+        }
+    }
+
     /// Append a files as a layer
+    ///
+    /// Files are stored in the top of the layer with sorted order.
     pub fn append_files(&mut self, ps: &[impl AsRef<Path>]) -> Result<()> {
-        let mut ar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
-        let mut files = Vec::new();
+        let mut entries = Vec::new();
         for path in ps {
             let path = path.as_ref();
             if !path.is_file() {
@@ -53,38 +89,61 @@ impl Builder {
                 .expect("This never fails since checked above")
                 .to_str()
                 .context("Non-UTF8 file name")?;
-            let mut f = fs::File::open(path)?;
-            files.push(PathBuf::from(name));
-            ar.append_file(name, &mut f)?;
-        }
-        let buf = ar.into_inner()?.finish()?;
-        let layer = self
-            .builder
-            .add_layer(media_types::layer_tar_gzip(), &buf, HashMap::new())?;
+            entries.push((PathBuf::from(name), path));
+        }
+        entries.sort();
+
+        let mut ar = tar::Builder::new(Vec::new());
+        let mut files = Vec::new();
+        for (name, path) in entries {
+            files.push(append_path(&mut ar, &name, path)?);
+        }
+        let buf = ar.into_inner()?;
+        let layer = self.add_layer(&buf)?;
         self.config.add_layer(layer.digest().clone(), files);
         Ok(())
     }
 
     /// Append directory as a layer
+    ///
+    /// Entries are stored in sorted order with normalized metadata to make the layer reproducible.
+    /// Every regular file, directory, and symbolic link in the directory is recorded in [Config].
     pub fn append_dir_all(&mut self, path: &Path) -> Result<()> {
         if !path.is_dir() {
             bail!("{} is not a directory", path.display());
         }
-        let paths = fs::read_dir(path)?
-            .filter_map(|entry| entry.ok().map(|e| e.path()))
-            .collect();
-
-        let mut ar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
-        ar.append_dir_all("", path)?;
-        let buf = ar.into_inner()?.finish()?;
-        let layer_desc =
-            self.builder
-                .add_layer(media_types::layer_tar_gzip(), &buf, HashMap::new())?;
-        self.config.add_layer(layer_desc.digest().clone(), paths);
+        let mut entries = Vec::new();
+        let mut ar = tar::Builder::new(Vec::new());
+        for entry in walkdir::WalkDir::new(path).min_depth(1).sort_by_file_name() {
+            let entry = entry?;
+            let name = entry
+                .path()
+                .strip_prefix(path)
+                .expect("WalkDir must return path under the root");
+            entries.push(append_path(&mut ar, name, entry.path())?);
+        }
+        let buf = ar.into_inner()?;
+        let layer_desc = self.add_layer(&buf)?;
+        self.config.add_layer(layer_desc.digest().clone(), entries);
         Ok(())
     }
 
+    /// Declare how to link the libraries in the artifact, see [LinkManifest]
+    pub fn set_link(&mut self, link: LinkManifest) {
+        self.config.set_link(link);
+    }
+
     pub fn build(mut self) -> Result<OciArtifact<OciArchive>> {
+        if let Some(link) = self.config.link() {
+            for lib in &link.libraries {
+                if !has_library(&self.config, link, &lib.name) {
+                    log::warn!(
+                        "Library `{}` declared to be linked is not found in the artifact",
+                        lib.name
+                    );
+                }
+            }
+        }
         self.builder.add_config(
             config_json(),
             self.config.to_json()?.as_bytes(),
@@ -94,6 +153,35 @@ impl Builder {
     }
 }
 
+/// Whether a file of the library, e.g. `libfoo.a` or `foo.dll`, exists in the search paths of [LinkManifest]
+fn has_library(config: &Config, link: &LinkManifest, name: &str) -> bool {
+    let top = [PathBuf::new()];
+    let search_paths = if link.search_paths.is_empty() {
+        &top[..]
+    } else {
+        &link.search_paths[..]
+    };
+    config.entries().any(|entry| {
+        let (Some(parent), Some(file_name)) = (entry.path.parent(), entry.path.file_name()) else {
+            return false;
+        };
+        let file_name = file_name.to_string_lossy();
+        let file_name = file_name.strip_prefix("lib").unwrap_or(&file_name);
+        search_paths.iter().any(|dir| dir == parent)
+            && file_name
+                .strip_prefix(name)
+                .is_some_and(|rest| rest.starts_with('.'))
+    })
+}
+
+/// Behavior of unpacking when the image already exists in local storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OnExisting {
+    Overwrite,
+    Fail,
+    Keep,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ArtifactVersion {
     /// Old style ocipkg artifact used in 0.2.x and before
@@ -106,6 +194,8 @@ pub enum ArtifactVersion {
 pub struct Artifact<Base: Image> {
     version: ArtifactVersion,
     base: OciArtifact<Base>,
+    private_key: Option<PrivateKey>,
+    max_unpack_size: u64,
 }
 
 impl<Base: Image> Deref for Artifact<Base> {
@@ -151,15 +241,62 @@ impl<Base: Image> Artifact<Base> {
                 return Ok(Self {
                     base,
                     version: ArtifactVersion::V1,
+                    private_key: None,
+                    max_unpack_size: DEFAULT_MAX_UNPACK_SIZE,
                 });
             }
         }
         Ok(Self {
             base,
             version: ArtifactVersion::V0,
+            private_key: None,
+            max_unpack_size: DEFAULT_MAX_UNPACK_SIZE,
         })
     }
 
+    pub fn version(&self) -> ArtifactVersion {
+        self.version
+    }
+
+    /// Set the cap of total size of files written by [Artifact::unpack] and [Artifact::extract]
+    ///
+    /// [DEFAULT_MAX_UNPACK_SIZE] is used if not set.
+    pub fn set_max_unpack_size(&mut self, size: u64) {
+        self.max_unpack_size = size;
+    }
+
+    /// Set private key to decrypt encrypted layers
+    ///
+    /// If not set, the key is loaded by [PrivateKey::load] when an encrypted layer is found.
+    pub fn set_private_key(&mut self, key: PrivateKey) {
+        self.private_key = Some(key);
+    }
+
+    /// Decrypt the layer if it is encrypted, and returns the media type of the decrypted layer
+    fn decrypt_layer(&mut self, desc: &Descriptor, blob: Vec<u8>) -> Result<(MediaType, Vec<u8>)> {
+        let Some(media_type) = media_types::decrypted(desc.media_type()) else {
+            return Ok((desc.media_type().clone(), blob));
+        };
+        let keys = desc
+            .annotations()
+            .as_ref()
+            .and_then(|annotations| annotations.get(ENC_KEYS_ANNOTATION))
+            .with_context(|| {
+                format!(
+                    "Encrypted layer {} does not have {ENC_KEYS_ANNOTATION} annotation",
+                    desc.digest()
+                )
+            })?;
+        if self.private_key.is_none() {
+            self.private_key = Some(
+                PrivateKey::load().context("Private key is required to decrypt the artifact")?,
+            );
+        }
+        let key = self.private_key.as_ref().unwrap();
+        let blob = encryption::decrypt(&blob, keys, key)?;
+        Ok((media_type, blob))
+    }
+
     pub fn get_ocipkg_config(&mut self) -> Result<Config> {
         if self.version == ArtifactVersion::V0 {
             bail!("ocipkg config is not available in v0 artifact");
@@ -174,80 +311,380 @@ impl<Base: Image> Artifact<Base> {
             ArtifactVersion::V0 => {
                 let mut files = Vec::new();
                 for (desc, blob) in self.base.get_layers()? {
-                    match desc.media_type() {
-                        MediaType::ImageLayer => {
-                            let mut ar = tar::Archive::new(blob.as_slice());
-                            for entry in ar.entries()? {
-                                let entry = entry?;
-                                let path = entry.path()?;
-                                files.push(path.to_path_buf());
-                            }
-                        }
-                        MediaType::ImageLayerGzip => {
-                            let buf = flate2::read::GzDecoder::new(blob.as_slice());
-                            let mut ar = tar::Archive::new(buf);
-                            for entry in ar.entries()? {
-                                let entry = entry?;
-                                let path = entry.path()?;
-                                files.push(path.to_path_buf());
-                            }
-                        }
-                        _ => bail!("Unsupported layer type: {}", desc.media_type()),
+                    let mut ar = tar::Archive::new(layer_reader(desc.media_type(), &blob)?);
+                    for entry in ar.entries()? {
+                        let entry = entry?;
+                        let path = entry.path()?;
+                        files.push(path.to_path_buf());
                     }
                 }
                 Ok(files)
             }
             ArtifactVersion::V1 => {
                 let config = self.get_ocipkg_config()?;
-                Ok(config.layers().values().flatten().cloned().collect())
+                Ok(config.entries().map(|entry| entry.path.clone()).collect())
+            }
+        }
+    }
+
+    /// Layers which may contain entries related to the predicate,
+    /// i.e. the entry itself or a directory containing the wanted entries matches
+    ///
+    /// [Config] is used to skip unrelated layers. Every layer is returned for v0 artifact,
+    /// and for config older than [CONFIG_VERSION], which records only top-level entries.
+    fn select_layers(&mut self, pred: impl Fn(&Path) -> bool) -> Result<Vec<Descriptor>> {
+        let manifest = self.base.get_manifest()?;
+        if self.version == ArtifactVersion::V0 {
+            return Ok(manifest.layers().clone());
+        }
+        let config = self.get_ocipkg_config()?;
+        if config.version() < CONFIG_VERSION {
+            return Ok(manifest.layers().clone());
+        }
+        Ok(manifest
+            .layers()
+            .iter()
+            .filter(|desc| {
+                config
+                    .layers()
+                    .get(desc.digest())
+                    .is_none_or(|entries| entries.iter().any(|entry| pred(&entry.path)))
+            })
+            .cloned()
+            .collect())
+    }
+
+    /// Get a layer blob, decrypted if it is encrypted
+    fn get_layer(&mut self, desc: &Descriptor) -> Result<(MediaType, Vec<u8>)> {
+        let blob = self.base.get_blob(desc.digest())?;
+        self.decrypt_layer(desc, blob)
+    }
+
+    /// Read a regular file stored in the artifact, and write its content into `writer`
+    ///
+    /// Only the layer containing the file is fetched. Returns the size of the file.
+    pub fn read_file(&mut self, path: &Path, writer: &mut impl io::Write) -> Result<u64> {
+        for desc in self.select_layers(|entry| path.starts_with(entry))? {
+            let (media_type, blob) = self.get_layer(&desc)?;
+            let mut ar = tar::Archive::new(layer_reader(&media_type, &blob)?);
+            for entry in ar.entries()? {
+                let mut entry = entry?;
+                if entry.path()? == path && entry.header().entry_type().is_file() {
+                    return Ok(io::copy(&mut entry, writer)?);
+                }
             }
         }
+        bail!("File not found in the artifact: {}", path.display())
+    }
+
+    /// Extract entries matching any of glob `patterns`, e.g. `include/**/*.h`, into `dest` directory
+    ///
+    /// Only the layers containing matched entries are fetched.
+    /// Entries are checked in the same manner as [Artifact::unpack].
+    /// Returns the relative paths of extracted entries.
+    pub fn extract(&mut self, patterns: &[impl AsRef<str>], dest: &Path) -> Result<Vec<PathBuf>> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern.as_ref())
+                    .with_context(|| format!("Invalid glob pattern: {}", pattern.as_ref()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let matches = |path: &Path| patterns.iter().any(|pattern| pattern.matches_path(path));
+        // Directory entry which may contain matched entries, e.g. `include` for `include/*.h`
+        let related = |entry: &Path| {
+            matches(entry)
+                || patterns
+                    .iter()
+                    .any(|pattern| Path::new(pattern.as_str()).starts_with(entry))
+        };
+
+        fs::create_dir_all(dest)?;
+        let mut unpacker = Unpacker::new(dest, self.max_unpack_size);
+        let mut extracted = Vec::new();
+        for desc in self.select_layers(related)? {
+            let (media_type, blob) = self.get_layer(&desc)?;
+            extracted.extend(unpacker.unpack(layer_reader(&media_type, &blob)?, matches)?);
+        }
+        Ok(extracted)
     }
 
     /// Unpack ocipkg artifact into local filesystem with `.oci-dir` directory
+    ///
+    /// Entries of layers are checked not to write outside of the image directory,
+    /// see [crate::image::unpack] for detail. For v1 artifact, unpacked files are verified against [Config].
+    ///
+    /// The artifact is unpacked into a temporary directory next to the image directory,
+    /// and renamed to the image directory after everything succeeded while holding [local::ImageLock].
+    /// Thus concurrent or interrupted unpacking never leaves a partially populated image directory.
     pub fn unpack(&mut self, overwrite: bool) -> Result<OciDir> {
+        self.unpack_impl(if overwrite {
+            OnExisting::Overwrite
+        } else {
+            OnExisting::Fail
+        })
+    }
+
+    /// Unpack unless the image already exists in local storage
+    ///
+    /// Unlike [Artifact::unpack] without overwrite, this succeeds
+    /// when another process has unpacked the same image concurrently.
+    pub fn unpack_if_missing(&mut self) -> Result<OciDir> {
+        self.unpack_impl(OnExisting::Keep)
+    }
+
+    fn unpack_impl(&mut self, on_existing: OnExisting) -> Result<OciDir> {
         let image_name = self.base.get_name()?;
         let dest = image_dir(&image_name)?;
+        let _lock = local::lock_image(&image_name)?;
         if dest.exists() {
-            if overwrite {
-                log::warn!(
-                    "Destination already exists: {}. Removing...",
-                    dest.display()
-                );
-                fs::remove_dir_all(&dest)?;
-            } else {
-                bail!("Destination already exists: {}", dest.display());
+            match on_existing {
+                OnExisting::Overwrite => {}
+                OnExisting::Fail => bail!("Destination already exists: {}", dest.display()),
+                OnExisting::Keep => return OciDir::new(&dest.join(".oci-dir")),
             }
         }
-        fs::create_dir_all(&dest)?;
-        let oci_dir = OciDirBuilder::new(dest.join(".oci-dir"), self.base.get_name()?)?;
-        let oci_dir = copy(self.base.deref_mut(), oci_dir)?;
-        for (desc, blob) in self.base.get_layers()? {
-            match (self.version, desc.media_type()) {
-                (ArtifactVersion::V0, MediaType::ImageLayer) => {
-                    let buf = blob.as_slice();
-                    tar::Archive::new(buf).unpack(&dest)?;
-                }
-                (ArtifactVersion::V0, MediaType::ImageLayerGzip) => {
-                    let buf = flate2::read::GzDecoder::new(blob.as_slice());
-                    tar::Archive::new(buf).unpack(&dest)?;
-                }
-                (ArtifactVersion::V1, media_type)
-                    if media_type == &media_types::layer_tar_gzip() =>
-                {
-                    let buf = flate2::read::GzDecoder::new(blob.as_slice());
-                    tar::Archive::new(buf).unpack(&dest)?;
+        self.unpack_replacing(&image_name, &dest)
+    }
+
+    /// Unpack into a directory out of local storage, e.g. under `OUT_DIR` of build script,
+    /// replacing the existing one
+    ///
+    /// Unlike [Artifact::unpack], no lock is taken, i.e. the caller must ensure that no other process writes `dest`.
+    pub fn unpack_to(&mut self, dest: &Path) -> Result<OciDir> {
+        let image_name = self.base.get_name()?;
+        self.unpack_replacing(&image_name, dest)
+    }
+
+    /// Unpack into a temporary directory next to `dest`, and replace `dest` with it
+    ///
+    /// The caller must hold the lock of `dest`, or ensure that no other process writes it.
+    /// Replacing is done by two renames, i.e. `dest` is missing for a moment in between,
+    /// and readers must take the lock to see a complete `dest`.
+    fn unpack_replacing(&mut self, image_name: &ImageName, dest: &Path) -> Result<OciDir> {
+        let parent = dest.parent().context("Destination must have parent")?;
+        fs::create_dir_all(parent)?;
+        let dirname = dest
+            .file_name()
+            .context("Destination must have name")?
+            .to_string_lossy();
+        remove_stale_siblings(parent, &dirname)?;
+        // Removed automatically when unpacking fails
+        let tmp = tempfile::Builder::new()
+            .prefix(&format!(".{dirname}.tmp"))
+            .tempdir_in(parent)?;
+        self.unpack_into(image_name, tmp.path())?;
+
+        if dest.exists() {
+            log::warn!(
+                "Destination already exists: {}. Replacing...",
+                dest.display()
+            );
+            let old = tempfile::Builder::new()
+                .prefix(&format!(".{dirname}.old"))
+                .tempdir_in(parent)?;
+            fs::rename(dest, old.path().join(&*dirname))?;
+            fs::rename(tmp.keep(), dest)?;
+            // `old` is removed here
+        } else {
+            fs::rename(tmp.keep(), dest)?;
+        }
+        OciDir::new(&dest.join(".oci-dir"))
+    }
+
+    fn unpack_into(&mut self, image_name: &ImageName, dest: &Path) -> Result<()> {
+        let source_digest = self.base.get_manifest_digest()?;
+        let oci_dir = OciDirBuilder::new(dest.join(".oci-dir"), image_name.clone())?
+            .with_blob_store(local::blob_store_dir()?)
+            .with_index_annotation(local::SOURCE_DIGEST_ANNOTATION, source_digest.as_ref());
+        // Read blobs from the local copy to avoid fetching them twice
+        let mut local = OciArtifact::new(copy(self.base.deref_mut(), oci_dir)?);
+        let mut unpacker = Unpacker::new(dest, self.max_unpack_size);
+        let mut unpacked = Vec::new();
+        for (desc, blob) in local.get_layers()? {
+            let (media_type, blob) = self.decrypt_layer(&desc, blob)?;
+            unpacked.extend(unpacker.unpack(layer_reader(&media_type, &blob)?, |path| {
+                if path.starts_with(".oci-dir") {
+                    log::warn!("Skip reserved path in layer: {}", path.display());
+                    return false;
                 }
-                _ => bail!("Unsupported layer type: {}", desc.media_type()),
+                true
+            })?);
+        }
+        if self.version == ArtifactVersion::V1 {
+            let config = Config::from_slice(&local.get_config()?.1)?;
+            let complete = config.version() >= CONFIG_VERSION;
+            match verify_unpacked(dest, config.entries(), &unpacked, complete) {
+                Ok(()) => {}
+                Err(e) if complete => return Err(e),
+                // Paths in version 1 config are not reliable, e.g. `ocipkg pack` in 0.4.x records input directory name
+                Err(e) => log::warn!("Unpacked files do not match to the legacy config: {e}"),
             }
         }
-        Ok(oci_dir)
+        Ok(())
     }
 }
 
 /// Load ocipkg artifact into local storage
 pub fn load(input: &Path, overwrite: bool) -> Result<()> {
-    let mut ar = Artifact::from_oci_archive(input)?;
+    let mut ar = Artifact::new(read_archive(input)?)?;
     ar.unpack(overwrite)?;
     Ok(())
 }
+
+/// Remove `.{dirname}.tmp*` and `.{dirname}.old*` directories left by [Artifact::unpack_replacing]
+/// when the process is killed before renaming or removing them
+fn remove_stale_siblings(parent: &Path, dirname: &str) -> Result<()> {
+    let prefixes = [format!(".{dirname}.tmp"), format!(".{dirname}.old")];
+    for entry in fs::read_dir(parent)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        // `tempfile` appends 6 random alphanumeric characters to the prefix
+        let stale = prefixes.iter().any(|prefix| {
+            name.strip_prefix(prefix.as_str()).is_some_and(|suffix| {
+                suffix.len() == 6 && suffix.chars().all(|c| c.is_ascii_alphanumeric())
+            })
+        });
+        if stale && entry.file_type()?.is_dir() {
+            log::warn!("Removing stale directory: {}", entry.path().display());
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reproducible_build() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let input = tmp_dir.path().join("input");
+        fs::create_dir_all(input.join("sub"))?;
+        fs::write(input.join("a.txt"), "a")?;
+        fs::write(input.join("sub/b.txt"), "b")?;
+
+        let image_name = ImageName::parse("localhost/reproducible:test")?;
+        let mut archives = Vec::new();
+        for i in 0..2 {
+            let output = tmp_dir.path().join(format!("out{i}.tar"));
+            let mut b = Builder::new(output.clone(), image_name.clone())?;
+            b.append_dir_all(&input)?;
+            b.append_files(&[input.join("sub/b.txt"), input.join("a.txt")])?;
+            b.build()?;
+            archives.push(fs::read(&output)?);
+            // Touch input files to change mtime
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            fs::write(input.join("a.txt"), "a")?;
+        }
+        assert!(archives[0] == archives[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn unpack_to_removes_stale_siblings() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let input = tmp_dir.path().join("input");
+        fs::create_dir_all(&input)?;
+        fs::write(input.join("a.txt"), "a")?;
+        let output = tmp_dir.path().join("out.tar");
+        let mut b = Builder::new(output.clone(), ImageName::parse("localhost/stale:test")?)?;
+        b.append_dir_all(&input)?;
+        b.build()?;
+
+        let out = tmp_dir.path().join("out");
+        fs::create_dir_all(&out)?;
+        for name in [".dest.tmpAbC123", ".dest.old0xYz9Q"] {
+            fs::create_dir_all(out.join(name).join("dest"))?;
+        }
+        // Not left by unpacking `dest`
+        fs::create_dir_all(out.join(".dest.tmpx.tmpAbC123"))?;
+
+        Artifact::from_oci_archive(&output)?.unpack_to(&out.join("dest"))?;
+        assert_eq!(fs::read_to_string(out.join("dest/a.txt"))?, "a");
+        let mut names = fs::read_dir(&out)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+        assert_eq!(names, [".dest.tmpx.tmpAbC123", "dest"]);
+        Ok(())
+    }
+
+    #[test]
+    fn extract() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let input = tmp_dir.path().join("input");
+        fs::create_dir_all(input.join("include"))?;
+        fs::write(input.join("include/a.h"), "a")?;
+        fs::write(input.join("include/b.h"), "b")?;
+        fs::write(input.join("liba.a"), "lib")?;
+
+        let output = tmp_dir.path().join("out.tar");
+        let mut b = Builder::new(output.clone(), ImageName::parse("localhost/extract:test")?)?;
+        b.append_dir_all(&input)?;
+        b.build()?;
+
+        let mut ar = Artifact::from_oci_archive(&output)?;
+        let mut buf = Vec::new();
+        ar.read_file(Path::new("include/b.h"), &mut buf)?;
+        assert_eq!(buf, b"b");
+        assert!(ar.read_file(Path::new("include"), &mut buf).is_err());
+
+        let dest = tmp_dir.path().join("dest");
+        let mut extracted = ar.extract(&["include/*.h"], &dest)?;
+        extracted.sort();
+        assert_eq!(
+            extracted,
+            [PathBuf::from("include/a.h"), PathBuf::from("include/b.h")]
+        );
+        assert_eq!(fs::read(dest.join("include/a.h"))?, b"a");
+        assert!(!dest.join("liba.a").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn extract_with_legacy_config() -> Result<()> {
+        use crate::image::InMemoryBuilder;
+
+        let tar = |path: &str, data: &[u8]| -> Result<Vec<u8>> {
+            let mut ar = tar::Builder::new(Vec::new());
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            ar.append_data(&mut header, path, data)?;
+            Ok(ar.into_inner()?)
+        };
+        let image_name = ImageName::parse("localhost/legacy:test")?;
+        let mut builder =
+            OciArtifactBuilder::new(InMemoryBuilder::new(image_name), media_types::artifact())?;
+        let include = builder.add_layer(
+            MediaType::ImageLayer,
+            &tar("include/foo.h", b"foo")?,
+            HashMap::new(),
+        )?;
+        let lib = builder.add_layer(
+            MediaType::ImageLayer,
+            &tar("libfoo.a", b"lib")?,
+            HashMap::new(),
+        )?;
+        // Version 1 config records only top-level entries
+        let config = format!(
+            r#"{{"layers":{{"{}":["include"],"{}":["libfoo.a"]}}}}"#,
+            include.digest(),
+            lib.digest()
+        );
+        builder.add_config(config_json(), config.as_bytes(), HashMap::new())?;
+        let mut ar = Artifact::new((*builder.build()?).clone())?;
+
+        let mut buf = Vec::new();
+        ar.read_file(Path::new("include/foo.h"), &mut buf)?;
+        assert_eq!(buf, b"foo");
+
+        let tmp_dir = tempfile::tempdir()?;
+        let extracted = ar.extract(&["include/*.h"], tmp_dir.path())?;
+        assert_eq!(extracted, [PathBuf::from("include/foo.h")]);
+        Ok(())
+    }
+}
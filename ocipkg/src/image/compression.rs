@@ -0,0 +1,265 @@
+use crate::media_types;
+use anyhow::{bail, Context, Result};
+use oci_spec::image::MediaType;
+use std::{
+    fmt, fs,
+    io::{self, Read, Seek, Write},
+    path::Path,
+    str::FromStr,
+};
+
+/// Compression algorithm and level of layers
+///
+/// This can be parsed from a string in the form of `{algorithm}` or `{algorithm}:{level}`:
+///
+/// ```
+/// use ocipkg::image::Compression;
+///
+/// assert_eq!("none".parse::<Compression>()?, Compression::None);
+/// assert_eq!("gzip".parse::<Compression>()?, Compression::Gzip(6));
+/// assert_eq!("zstd:19".parse::<Compression>()?, Compression::Zstd(19));
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Compression {
+    /// Uncompressed tar
+    None,
+    /// gzip with compression level 0-9
+    Gzip(u32),
+    /// zstd with compression level 1-22
+    Zstd(i32),
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::Gzip(flate2::Compression::default().level())
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Gzip(level) => write!(f, "gzip:{level}"),
+            Self::Zstd(level) => write!(f, "zstd:{level}"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (algorithm, level) = match s.split_once(':') {
+            Some((algorithm, level)) => (
+                algorithm,
+                Some(level.parse::<i32>().context("Invalid compression level")?),
+            ),
+            None => (s, None),
+        };
+        Ok(match algorithm {
+            "none" => {
+                if level.is_some() {
+                    bail!("Compression level cannot be specified for `none`");
+                }
+                Self::None
+            }
+            "gzip" => match level {
+                Some(level @ 0..=9) => Self::Gzip(level as u32),
+                Some(level) => bail!("gzip compression level must be in 0-9: {level}"),
+                None => Self::default(),
+            },
+            "zstd" => match level {
+                Some(level @ 1..=22) => Self::Zstd(level),
+                Some(level) => bail!("zstd compression level must be in 1-22: {level}"),
+                None => Self::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL),
+            },
+            _ => bail!("Unknown compression algorithm: {algorithm}"),
+        })
+    }
+}
+
+impl Compression {
+    /// Media type of ocipkg layer compressed by this algorithm
+    pub fn layer_media_type(&self) -> MediaType {
+        match self {
+            Self::None => media_types::layer_tar(),
+            Self::Gzip(_) => media_types::layer_tar_gzip(),
+            Self::Zstd(_) => media_types::layer_tar_zstd(),
+        }
+    }
+
+    pub fn compress(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::None => buf.to_vec(),
+            Self::Gzip(level) => {
+                let mut enc =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(*level));
+                enc.write_all(buf)?;
+                enc.finish()?
+            }
+            Self::Zstd(level) => zstd::encode_all(buf, *level)?,
+        })
+    }
+
+    /// Compress the stream from `input` into `output`
+    pub(crate) fn compress_stream(&self, input: &mut impl Read, output: impl Write) -> Result<()> {
+        match self {
+            Self::None => {
+                let mut output = output;
+                io::copy(input, &mut output)?;
+            }
+            Self::Gzip(level) => {
+                let mut enc =
+                    flate2::write::GzEncoder::new(output, flate2::Compression::new(*level));
+                io::copy(input, &mut enc)?;
+                enc.finish()?;
+            }
+            Self::Zstd(level) => zstd::stream::copy_encode(input, output, *level)?,
+        }
+        Ok(())
+    }
+
+    /// Reader decompressing the stream from `input`
+    pub(crate) fn decoder<'a>(&self, input: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Self::None => Box::new(input),
+            Self::Gzip(_) => Box::new(flate2::read::GzDecoder::new(input)),
+            Self::Zstd(_) => Box::new(zstd::Decoder::new(input)?),
+        })
+    }
+
+    /// Detect compression from the magic bytes at the head of data
+    ///
+    /// The level is set to the default one since it cannot be detected.
+    pub(crate) fn from_magic(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Self::default()
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)
+        } else {
+            Self::None
+        }
+    }
+
+    /// Detect compression of the file from its magic bytes, and rewind it
+    pub(crate) fn detect(f: &mut fs::File) -> Result<Self> {
+        let mut magic = Vec::with_capacity(4);
+        f.take(4).read_to_end(&mut magic)?;
+        f.rewind()?;
+        Ok(Self::from_magic(&magic))
+    }
+
+    /// Guess compression of an archive from the extension of its path,
+    /// e.g. `.tar.gz` or `.tgz` for gzip, and `.tar.zst` or `.tzst` for zstd
+    pub fn from_extension(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz" | "tgz") => Self::default(),
+            Some("zst" | "tzst") => Self::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL),
+            _ => Self::None,
+        }
+    }
+}
+
+/// Open a tar archive, which may be compressed by gzip or zstd
+///
+/// Compressed archive is expanded into an anonymous temporary file to allow random access.
+/// The detected compression is returned with the tar file.
+pub(crate) fn open_tar(path: &Path) -> Result<(fs::File, Compression)> {
+    let mut f = fs::File::open(path)?;
+    let compression = Compression::detect(&mut f)?;
+    if compression == Compression::None {
+        return Ok((f, compression));
+    }
+    let mut tmp = tempfile::tempfile()?;
+    io::copy(&mut compression.decoder(f)?, &mut tmp)
+        .with_context(|| format!("Failed to decompress {}", path.display()))?;
+    tmp.rewind()?;
+    Ok((tmp, compression))
+}
+
+/// Reader of the tar archive stored in a layer of given media type
+///
+/// Both ocipkg layers `application/vnd.ocipkg.v1.layer.tar*`
+/// and OCI image layers `application/vnd.oci.image.layer.v1.tar*` are supported.
+pub(crate) fn layer_reader<'a>(
+    media_type: &MediaType,
+    blob: &'a [u8],
+) -> Result<Box<dyn Read + 'a>> {
+    Ok(match media_type {
+        MediaType::ImageLayer => Box::new(blob),
+        MediaType::ImageLayerGzip => Box::new(flate2::read::GzDecoder::new(blob)),
+        MediaType::ImageLayerZstd => Box::new(zstd::Decoder::new(blob)?),
+        media_type if media_type == &media_types::layer_tar() => Box::new(blob),
+        media_type if media_type == &media_types::layer_tar_gzip() => {
+            Box::new(flate2::read::GzDecoder::new(blob))
+        }
+        media_type if media_type == &media_types::layer_tar_zstd() => {
+            Box::new(zstd::Decoder::new(blob)?)
+        }
+        _ => bail!("Unsupported layer type: {}", media_type),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let mut ar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_cksum();
+        ar.append_data(&mut header, "a.txt", b"hello".as_slice())?;
+        let tar = ar.into_inner()?;
+
+        for compression in [
+            Compression::None,
+            Compression::Gzip(9),
+            Compression::Zstd(3),
+        ] {
+            let blob = compression.compress(&tar)?;
+            let mut ar = tar::Archive::new(layer_reader(&compression.layer_media_type(), &blob)?);
+            let mut entries = ar.entries()?;
+            let mut entry = entries.next().unwrap()?;
+            assert_eq!(entry.path()?, std::path::Path::new("a.txt"));
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            assert_eq!(content, "hello");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn stream() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        for compression in [
+            Compression::None,
+            Compression::Gzip(9),
+            Compression::Zstd(3),
+        ] {
+            let path = tmp_dir.path().join(compression.to_string());
+            compression.compress_stream(&mut b"hello".as_slice(), fs::File::create(&path)?)?;
+            assert_eq!(
+                Compression::detect(&mut fs::File::open(&path)?)?.layer_media_type(),
+                compression.layer_media_type()
+            );
+            let mut content = String::new();
+            open_tar(&path)?.0.read_to_string(&mut content)?;
+            assert_eq!(content, "hello");
+        }
+        assert_eq!(
+            Compression::from_extension(Path::new("a.tar.gz")),
+            Compression::default()
+        );
+        assert_eq!(
+            Compression::from_extension(Path::new("a.tzst")),
+            Compression::Zstd(3)
+        );
+        assert_eq!(
+            Compression::from_extension(Path::new("a.tar")),
+            Compression::None
+        );
+        Ok(())
+    }
+}
@@ -1,31 +1,444 @@
-use anyhow::Result;
+use crate::image::reproducible::to_canonical_json;
+use anyhow::{bail, Result};
 use oci_spec::image::Digest;
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// The latest version of [Config] schema
+pub const CONFIG_VERSION: u32 = 2;
 
 /// The contents of `application/vnd.ocipkg.v1.config+json` media type.
 ///
-/// This is a map from the layer digest to the list of relative paths of the files in the layer.
+/// This is a map from the layer digest to the list of files in the layer.
+///
+/// - Version 1 (ocipkg 0.3.x and 0.4.x) stores only the relative paths of the top-level entries:
+///
+///   ```json
+///   { "layers": { "sha256:...": ["lib.a"] } }
+///   ```
+///
+/// - Version 2 stores every regular file, directory, and symbolic link in the layer recursively with its metadata:
+///
+///   ```json
+///   {
+///     "version": 2,
+///     "layers": {
+///       "sha256:...": [
+///         { "path": "lib.a", "type": "file", "size": 1024, "mode": 420, "digest": "sha256:..." }
+///       ]
+///     }
+///   }
+///   ```
 ///
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+/// Both versions can be read, and version 2 is always written.
+///
+/// In addition, the config may have a `link` field of [LinkManifest] declaring how to link the libraries in the artifact:
+///
+/// ```json
+/// {
+///   "link": {
+///     "libraries": [{ "name": "foo", "kind": "static" }],
+///     "search_paths": ["lib"],
+///     "system_libraries": ["stdc++"]
+///   }
+/// }
+/// ```
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
-    layers: HashMap<Digest, Vec<PathBuf>>,
+    #[serde(default = "legacy_version")]
+    version: u32,
+    #[serde(deserialize_with = "deserialize_layers")]
+    layers: HashMap<Digest, Vec<FileEntry>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link: Option<LinkManifest>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            layers: HashMap::new(),
+            link: None,
+        }
+    }
+}
+
+fn legacy_version() -> u32 {
+    1
+}
+
+fn deserialize_layers<'de, D>(deserializer: D) -> Result<HashMap<Digest, Vec<FileEntry>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum AnyFileEntry {
+        /// Version 1
+        Path(PathBuf),
+        /// Version 2
+        Entry(FileEntry),
+    }
+
+    let layers: HashMap<Digest, Vec<AnyFileEntry>> = HashMap::deserialize(deserializer)?;
+    Ok(layers
+        .into_iter()
+        .map(|(digest, entries)| {
+            let entries = entries
+                .into_iter()
+                .map(|entry| match entry {
+                    AnyFileEntry::Path(path) => FileEntry::from_path(path),
+                    AnyFileEntry::Entry(entry) => entry,
+                })
+                .collect();
+            (digest, entries)
+        })
+        .collect())
 }
 
 impl Config {
     pub fn to_json(&self) -> Result<String> {
-        Ok(serde_json::to_string(self)?)
+        to_canonical_json(self)
     }
 
     pub fn from_slice(slice: &[u8]) -> Result<Self> {
         Ok(serde_json::from_slice(slice)?)
     }
 
-    pub fn add_layer(&mut self, digest: Digest, paths: Vec<PathBuf>) {
-        self.layers.insert(digest, paths);
+    /// Version of the schema this config is read from
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn add_layer(&mut self, digest: Digest, entries: Vec<FileEntry>) {
+        self.layers.insert(digest, entries);
     }
 
-    pub fn layers(&self) -> &HashMap<Digest, Vec<PathBuf>> {
+    pub fn layers(&self) -> &HashMap<Digest, Vec<FileEntry>> {
         &self.layers
     }
+
+    /// All entries in all layers
+    pub fn entries(&self) -> impl Iterator<Item = &FileEntry> {
+        self.layers.values().flatten()
+    }
+
+    /// How to link the libraries in the artifact, if declared
+    pub fn link(&self) -> Option<&LinkManifest> {
+        self.link.as_ref()
+    }
+
+    pub fn set_link(&mut self, link: LinkManifest) {
+        self.link = Some(link);
+    }
+}
+
+/// Link instructions declared by the artifact, used by `link_package` instead of guessing from file names
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkManifest {
+    /// Libraries in the artifact to be linked
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub libraries: Vec<LinkLibrary>,
+    /// Directories in the artifact searched by the linker. The top directory is searched if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub search_paths: Vec<PathBuf>,
+    /// Directories in the artifact containing headers. `include` is used if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include_paths: Vec<PathBuf>,
+    /// Libraries provided by the system and required by the libraries, e.g. `stdc++` or `pthread`.
+    /// The kind can be specified like `dylib=stdc++` or `framework=CoreFoundation`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub system_libraries: Vec<String>,
+    /// Configurations enabled for crates linking the artifact, e.g. `has_foo` or `foo_version="2"`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cfgs: Vec<String>,
+}
+
+impl LinkManifest {
+    pub fn is_empty(&self) -> bool {
+        self.libraries.is_empty()
+            && self.search_paths.is_empty()
+            && self.include_paths.is_empty()
+            && self.system_libraries.is_empty()
+            && self.cfgs.is_empty()
+    }
+
+    /// Check the values can be emitted as build script instructions safely
+    ///
+    /// The manifest comes from a downloaded artifact, and a value containing e.g. a newline
+    /// would inject an arbitrary `cargo:` instruction.
+    pub fn validate(&self) -> Result<()> {
+        for path in self.search_paths.iter().chain(&self.include_paths) {
+            match path.to_str() {
+                Some(s) if !s.chars().any(char::is_control) => {}
+                _ => bail!("Invalid path in link manifest: {path:?}"),
+            }
+        }
+        for lib in &self.libraries {
+            validate_library_name(&lib.name)?;
+        }
+        for lib in &self.system_libraries {
+            let name = match lib.split_once('=') {
+                Some(("static" | "dylib" | "framework", name)) => name,
+                Some((kind, _)) => bail!(
+                    "Unknown system library kind `{kind}`, must be `static`, `dylib`, or `framework`"
+                ),
+                None => lib,
+            };
+            validate_library_name(name)?;
+        }
+        for cfg in &self.cfgs {
+            let valid = match cfg.split_once('=') {
+                Some((key, value)) => {
+                    is_identifier(key)
+                        && value.len() >= 2
+                        && value.starts_with('"')
+                        && value.ends_with('"')
+                        && value[1..value.len() - 1]
+                            .chars()
+                            .all(|c| !c.is_control() && c != '"' && c != '\\')
+                }
+                None => is_identifier(cfg),
+            };
+            if !valid {
+                bail!("Invalid cfg `{cfg}`, must be `name` or `key=\"value\"`");
+            }
+        }
+        Ok(())
+    }
+}
+
+fn validate_library_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.chars().any(|c| c.is_control() || c == '=' || c == ':') {
+        bail!("Invalid library name: {name:?}");
+    }
+    Ok(())
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Kind of [LinkLibrary]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LibraryKind {
+    Static,
+    Dylib,
+}
+
+/// A library in the artifact declared in [LinkManifest]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LinkLibrary {
+    /// Name of the library without prefix and extension, e.g. `foo` for `libfoo.a`
+    pub name: String,
+    pub kind: LibraryKind,
+}
+
+impl FromStr for LinkLibrary {
+    type Err = anyhow::Error;
+
+    /// Parse `[static|dylib=]name` as the `-l` option of rustc. The kind is `static` if omitted.
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, name) = match s.split_once('=') {
+            Some(("static", name)) => (LibraryKind::Static, name),
+            Some(("dylib", name)) => (LibraryKind::Dylib, name),
+            Some((kind, _)) => bail!("Unknown library kind `{kind}`, must be `static` or `dylib`"),
+            None => (LibraryKind::Static, s),
+        };
+        validate_library_name(name)?;
+        Ok(Self {
+            name: name.to_string(),
+            kind,
+        })
+    }
+}
+
+impl fmt::Display for LinkLibrary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            LibraryKind::Static => write!(f, "static={}", self.name),
+            LibraryKind::Dylib => write!(f, "dylib={}", self.name),
+        }
+    }
+}
+
+/// Type of an entry in a layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// An entry in a layer recorded in [Config]
+///
+/// Metadata are `None` if the config is read from version 1 schema.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+    /// Relative path in the layer
+    pub path: PathBuf,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub file_type: Option<FileType>,
+    /// Size in bytes of a regular file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Permission bits stored in the layer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// SHA256 digest of the content of a regular file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<Digest>,
+    /// Target of a symbolic link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+}
+
+impl FileEntry {
+    /// An entry without metadata
+    pub fn from_path(path: PathBuf) -> Self {
+        Self {
+            path,
+            file_type: None,
+            size: None,
+            mode: None,
+            digest: None,
+            target: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const DIGEST: &str = "sha256:a1b2c3d4a1b2c3d4a1b2c3d4a1b2c3d4a1b2c3d4a1b2c3d4a1b2c3d4a1b2c3d4";
+
+    #[test]
+    fn read_v1() -> Result<()> {
+        let config =
+            Config::from_slice(format!(r#"{{"layers":{{"{DIGEST}":["lib.a"]}}}}"#).as_bytes())?;
+        assert_eq!(config.version(), 1);
+        let entries = &config.layers()[&Digest::from_str(DIGEST)?];
+        assert_eq!(entries, &[FileEntry::from_path("lib.a".into())]);
+        Ok(())
+    }
+
+    #[test]
+    fn roundtrip_v2() -> Result<()> {
+        let mut config = Config::default();
+        config.add_layer(
+            Digest::from_str(DIGEST)?,
+            vec![
+                FileEntry {
+                    path: "include".into(),
+                    file_type: Some(FileType::Directory),
+                    mode: Some(0o755),
+                    ..FileEntry::from_path(PathBuf::new())
+                },
+                FileEntry {
+                    path: "include/lib.h".into(),
+                    file_type: Some(FileType::File),
+                    size: Some(3),
+                    mode: Some(0o644),
+                    digest: Some(Digest::from_str(DIGEST)?),
+                    target: None,
+                },
+            ],
+        );
+        let json = config.to_json()?;
+        let read = Config::from_slice(json.as_bytes())?;
+        assert_eq!(read.version(), CONFIG_VERSION);
+        assert_eq!(read.layers(), config.layers());
+        assert!(read.link().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn link_manifest() -> Result<()> {
+        let link = LinkManifest {
+            libraries: vec!["foo".parse()?, "dylib=bar".parse()?],
+            search_paths: vec!["lib".into()],
+            include_paths: Vec::new(),
+            system_libraries: vec!["stdc++".into()],
+            cfgs: Vec::new(),
+        };
+        assert_eq!(link.libraries[0].kind, LibraryKind::Static);
+        assert_eq!(link.libraries[1].to_string(), "dylib=bar");
+        assert!("framework=baz".parse::<LinkLibrary>().is_err());
+
+        let mut config = Config::default();
+        config.set_link(link.clone());
+        let json = config.to_json()?;
+        assert!(!json.contains("cfgs"));
+        let read = Config::from_slice(json.as_bytes())?;
+        assert_eq!(read.link(), Some(&link));
+        Ok(())
+    }
+
+    #[test]
+    fn validate_link_manifest() -> Result<()> {
+        let valid = LinkManifest {
+            libraries: vec!["foo".parse()?],
+            system_libraries: vec!["stdc++".into(), "framework=CoreFoundation".into()],
+            cfgs: vec!["has_foo".into(), r#"foo_version="2""#.into()],
+            search_paths: vec!["lib/x86_64".into()],
+            include_paths: vec!["include".into()],
+        };
+        valid.validate()?;
+
+        let invalid = [
+            LinkManifest {
+                libraries: vec![LinkLibrary {
+                    name: "foo\ncargo:rustc-link-arg=-evil".into(),
+                    kind: LibraryKind::Static,
+                }],
+                ..Default::default()
+            },
+            LinkManifest {
+                system_libraries: vec!["m\ncargo:rustc-link-arg=-evil".into()],
+                ..Default::default()
+            },
+            LinkManifest {
+                system_libraries: vec!["link-arg=-evil".into()],
+                ..Default::default()
+            },
+            LinkManifest {
+                search_paths: vec!["lib\ncargo:rustc-link-arg=-evil".into()],
+                ..Default::default()
+            },
+            LinkManifest {
+                include_paths: vec!["include\rcargo:warning=evil".into()],
+                ..Default::default()
+            },
+            LinkManifest {
+                cfgs: vec!["foo\ncargo:rustc-env=A=B".into()],
+                ..Default::default()
+            },
+            LinkManifest {
+                cfgs: vec![r#"foo="a"b""#.into()],
+                ..Default::default()
+            },
+        ];
+        for link in invalid {
+            assert!(link.validate().is_err(), "{link:?}");
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,505 @@
+use crate::{
+    digest::DigestExt,
+    image::{
+        compression::open_tar, get_manifest_digest_from_index, get_name_from_index,
+        reproducible::*, Compression, Image, ImageBuilder,
+    },
+    ImageName,
+};
+use anyhow::{bail, Context, Result};
+use oci_spec::image::{
+    Descriptor, DescriptorBuilder, Digest, ImageIndex, ImageIndexBuilder, ImageManifest,
+    ImageManifestBuilder, MediaType,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+/// An entry of `manifest.json` in docker-archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+/// Build a [DockerArchive]
+///
+/// In addition to `manifest.json` and `repositories` read by `docker load`,
+/// the OCI image layout, i.e. `index.json` and the manifest blob, is also written
+/// as `docker save` of Docker 25 or later does. Thus media types of ocipkg artifacts are preserved.
+///
+/// Note that `docker load` accepts only container images, not ocipkg artifacts.
+pub struct DockerArchiveBuilder {
+    image_name: Option<ImageName>,
+    path: PathBuf,
+    ar: tar::Builder<fs::File>,
+}
+
+impl DockerArchiveBuilder {
+    pub fn new_unnamed(path: PathBuf) -> Result<Self> {
+        if path.exists() {
+            bail!("File already exists: {}", path.display());
+        }
+        let f = fs::File::create(&path)?;
+        let ar = tar::Builder::new(f);
+        Ok(Self {
+            ar,
+            path,
+            image_name: None,
+        })
+    }
+
+    pub fn new(path: PathBuf, image_name: ImageName) -> Result<Self> {
+        let mut builder = Self::new_unnamed(path)?;
+        builder.image_name = Some(image_name);
+        Ok(builder)
+    }
+
+    fn append(&mut self, path: &str, buf: &[u8]) -> Result<()> {
+        self.ar
+            .append_data(&mut create_file_header(buf.len())?, path, buf)?;
+        Ok(())
+    }
+}
+
+impl ImageBuilder for DockerArchiveBuilder {
+    type Image = DockerArchive;
+
+    fn add_blob(&mut self, blob: &[u8]) -> Result<(Digest, u64)> {
+        let digest = Digest::eval_sha256_digest(blob);
+        self.ar
+            .append_data(&mut create_file_header(blob.len())?, digest.as_path(), blob)?;
+        Ok((digest, blob.len() as u64))
+    }
+
+    fn build(mut self, manifest: ImageManifest) -> Result<Self::Image> {
+        let manifest_json = to_canonical_json(&manifest)?;
+        let (digest, size) = self.add_blob(manifest_json.as_bytes())?;
+        let mut annotations = HashMap::new();
+        if let Some(name) = &self.image_name {
+            annotations.insert(
+                "org.opencontainers.image.ref.name".to_string(),
+                name.to_string(),
+            );
+        }
+        let descriptor = DescriptorBuilder::default()
+            .media_type(MediaType::ImageManifest)
+            .size(size)
+            .digest(digest)
+            .annotations(annotations)
+            .build()?;
+        let index = ImageIndexBuilder::default()
+            .schema_version(2_u32)
+            .manifests(vec![descriptor])
+            .build()?;
+        self.append("index.json", to_canonical_json(&index)?.as_bytes())?;
+        self.append("oci-layout", br#"{"imageLayoutVersion":"1.0.0"}"#)?;
+
+        // Docker accepts only tags, not digests, in `RepoTags`
+        let tagged = self
+            .image_name
+            .clone()
+            .filter(|name| !name.reference.contains(':'));
+        let blob_path = |desc: &Descriptor| desc.digest().as_path().to_string_lossy().to_string();
+        let docker_manifest = DockerManifest {
+            config: blob_path(manifest.config()),
+            repo_tags: tagged.as_ref().map(|name| vec![name.to_string()]),
+            layers: manifest.layers().iter().map(blob_path).collect(),
+        };
+        self.append(
+            "manifest.json",
+            to_canonical_json(&[docker_manifest])?.as_bytes(),
+        )?;
+
+        if let (Some(name), Some(top)) = (&tagged, manifest.layers().last()) {
+            let repository = match name.port {
+                Some(port) => format!("{}:{}/{}", name.hostname, port, name.name),
+                None => format!("{}/{}", name.hostname, name.name),
+            };
+            let repositories = BTreeMap::from([(
+                repository,
+                BTreeMap::from([(
+                    name.reference.to_string(),
+                    top.digest().digest().to_string(),
+                )]),
+            )]);
+            self.append("repositories", to_canonical_json(&repositories)?.as_bytes())?;
+        }
+
+        self.ar.finish()?;
+        DockerArchive::new(&self.path)
+    }
+}
+
+fn create_file_header(size: usize) -> Result<tar::Header> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size as u64);
+    header.set_mode(0o644);
+    // Use fixed mtime for reproducible build
+    header.set_mtime(source_date_epoch()?);
+    header.set_cksum();
+    Ok(header)
+}
+
+/// A regular file in docker-archive
+#[derive(Debug, Clone)]
+struct FileInfo {
+    digest: Digest,
+    offset: u64,
+    size: u64,
+    media_type: MediaType,
+}
+
+/// `docker-archive` created by `docker save`
+///
+/// Both the legacy format storing layers as `{id}/layer.tar` and the format of Docker 25 or later
+/// storing blobs in OCI image layout are supported. If the archive contains `index.json`,
+/// the OCI manifest is used as is. Otherwise, a manifest is composed from `manifest.json`,
+/// where layers are `application/vnd.oci.image.layer.v1.tar` possibly with compression detected from their contents.
+///
+/// The archive may be compressed by gzip or zstd, e.g. `docker save | gzip`.
+/// Only archives of a single image are supported.
+pub struct DockerArchive {
+    // Decompressed tar archive
+    f: fs::File,
+    docker_manifest: DockerManifest,
+    index: Option<ImageIndex>,
+    files: HashMap<String, FileInfo>,
+    symlinks: HashMap<String, String>,
+    paths: HashMap<Digest, String>,
+}
+
+impl DockerArchive {
+    pub fn new(path: &Path) -> Result<Self> {
+        if !path.is_file() {
+            bail!("Not a file: {}", path.display());
+        }
+        let (f, _) = open_tar(path)?;
+        let mut ar = tar::Archive::new(&f);
+        let mut docker_manifests: Option<Vec<DockerManifest>> = None;
+        let mut index = None;
+        let mut files = HashMap::new();
+        let mut symlinks = HashMap::new();
+        for entry in ar.entries_with_seek()? {
+            let mut entry = entry?;
+            let name = normalize(&entry.path()?)?;
+            match entry.header().entry_type() {
+                tar::EntryType::Symlink | tar::EntryType::Link => {
+                    let target = entry
+                        .link_name()?
+                        .with_context(|| format!("Link without target: {name}"))?;
+                    let target = if entry.header().entry_type() == tar::EntryType::Symlink {
+                        Path::new(&name)
+                            .parent()
+                            .unwrap_or(Path::new(""))
+                            .join(target)
+                    } else {
+                        target.into_owned()
+                    };
+                    symlinks.insert(name, normalize(&target)?);
+                    continue;
+                }
+                tar::EntryType::Regular | tar::EntryType::Continuous => {}
+                _ => continue,
+            }
+            match name.as_str() {
+                "manifest.json" => docker_manifests = Some(serde_json::from_reader(entry)?),
+                "index.json" => index = Some(ImageIndex::from_reader(entry)?),
+                _ => {
+                    let offset = entry.raw_file_position();
+                    files.insert(name, hash(&mut entry, offset)?);
+                }
+            }
+        }
+        let mut docker_manifests = docker_manifests.context("Missing manifest.json")?;
+        if docker_manifests.len() != 1 {
+            bail!("Multiple images in a docker-archive, it is not allowed in ocipkg.");
+        }
+        let paths = files
+            .iter()
+            .map(|(path, info)| (info.digest.clone(), path.clone()))
+            .collect();
+        Ok(Self {
+            f,
+            docker_manifest: docker_manifests.pop().unwrap(),
+            index,
+            files,
+            symlinks,
+            paths,
+        })
+    }
+
+    /// Whether the tar archive at `path` is docker-archive, i.e. has `manifest.json`
+    pub fn is_docker_archive(path: &Path) -> Result<bool> {
+        let mut f = fs::File::open(path)?;
+        let compression = Compression::detect(&mut f)?;
+        if compression == Compression::None {
+            has_manifest(tar::Archive::new(f).entries_with_seek()?)
+        } else {
+            has_manifest(tar::Archive::new(compression.decoder(f)?).entries()?)
+        }
+    }
+
+    /// Manifest in the OCI image layout stored with docker-archive
+    fn oci_manifest_desc(&self) -> Option<&Descriptor> {
+        let desc = self.index.as_ref()?.manifests().first()?;
+        (desc.media_type() == &MediaType::ImageManifest && self.paths.contains_key(desc.digest()))
+            .then_some(desc)
+    }
+
+    fn file(&self, path: &str) -> Result<&FileInfo> {
+        let mut path = normalize(Path::new(path))?;
+        // Layers shared by multiple images are symlinked in the legacy format
+        for _ in 0..16 {
+            match self.symlinks.get(&path) {
+                Some(target) => path = target.clone(),
+                None => break,
+            }
+        }
+        self.files
+            .get(&path)
+            .with_context(|| format!("Missing file in docker-archive: {path}"))
+    }
+
+    fn descriptor(&self, path: &str, media_type: Option<MediaType>) -> Result<Descriptor> {
+        let info = self.file(path)?;
+        Ok(DescriptorBuilder::default()
+            .media_type(media_type.unwrap_or_else(|| info.media_type.clone()))
+            .digest(info.digest.clone())
+            .size(info.size)
+            .build()?)
+    }
+}
+
+impl Image for DockerArchive {
+    fn get_name(&mut self) -> Result<ImageName> {
+        if let Some(tag) = self
+            .docker_manifest
+            .repo_tags
+            .as_ref()
+            .and_then(|tags| tags.first())
+        {
+            return ImageName::parse(tag);
+        }
+        match &self.index {
+            Some(index) => get_name_from_index(index),
+            None => bail!("RepoTags is not found in manifest.json"),
+        }
+    }
+
+    fn get_blob(&mut self, digest: &Digest) -> Result<Vec<u8>> {
+        let path = self
+            .paths
+            .get(digest)
+            .with_context(|| format!("Missing blob: {digest}"))?;
+        let info = &self.files[path];
+        self.f.seek(SeekFrom::Start(info.offset))?;
+        let mut buf = vec![0; info.size as usize];
+        self.f.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn get_manifest(&mut self) -> Result<ImageManifest> {
+        if let Some(desc) = self.oci_manifest_desc() {
+            let digest = desc.digest().clone();
+            return Ok(serde_json::from_slice(&self.get_blob(&digest)?)?);
+        }
+        let config = self.descriptor(&self.docker_manifest.config, Some(MediaType::ImageConfig))?;
+        let layers = self
+            .docker_manifest
+            .layers
+            .iter()
+            .map(|path| self.descriptor(path, None))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ImageManifestBuilder::default()
+            .schema_version(2_u32)
+            .media_type(MediaType::ImageManifest)
+            .config(config)
+            .layers(layers)
+            .build()?)
+    }
+
+    fn get_manifest_digest(&mut self) -> Result<Digest> {
+        if self.oci_manifest_desc().is_some() {
+            return get_manifest_digest_from_index(self.index.as_ref().unwrap());
+        }
+        let manifest = self.get_manifest()?;
+        Ok(Digest::eval_sha256_digest(
+            to_canonical_json(&manifest)?.as_bytes(),
+        ))
+    }
+}
+
+fn has_manifest<R: Read>(entries: tar::Entries<'_, R>) -> Result<bool> {
+    for entry in entries {
+        if normalize(&entry?.path()?)? == "manifest.json" {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Digest, size, and media type detected from magic bytes of a file in the archive
+fn hash(reader: &mut impl Read, offset: u64) -> Result<FileInfo> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut size = 0;
+    let mut magic = Vec::new();
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if magic.len() < 4 {
+            magic.extend_from_slice(&buf[..n.min(4 - magic.len())]);
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    let digest = Digest::from_str(&format!(
+        "sha256:{}",
+        base16ct::lower::encode_string(&hasher.finalize())
+    ))?;
+    let media_type = match Compression::from_magic(&magic) {
+        Compression::None => MediaType::ImageLayer,
+        Compression::Gzip(_) => MediaType::ImageLayerGzip,
+        Compression::Zstd(_) => MediaType::ImageLayerZstd,
+    };
+    Ok(FileInfo {
+        digest,
+        offset,
+        size,
+        media_type,
+    })
+}
+
+/// Normalize a path in the archive, e.g. `./a/../b` into `b`
+fn normalize(path: &Path) -> Result<String> {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => out.push(c),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !out.pop() {
+                    bail!("Path points outside of archive: {}", path.display());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    Ok(out
+        .to_str()
+        .context("Non UTF-8 path in archive")?
+        .replace('\\', "/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{copy, OciArchive};
+
+    fn append(ar: &mut tar::Builder<fs::File>, path: &str, data: &[u8]) -> Result<()> {
+        ar.append_data(&mut create_file_header(data.len())?, path, data)?;
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_format() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("docker.tar");
+        let layer = {
+            let mut ar = tar::Builder::new(Vec::new());
+            ar.append_data(&mut create_file_header(2)?, "hello.txt", &b"hi"[..])?;
+            ar.into_inner()?
+        };
+        let config =
+            br#"{"architecture":"amd64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}"#;
+        {
+            let mut ar = tar::Builder::new(fs::File::create(&path)?);
+            append(&mut ar, "aaaa/layer.tar", &layer)?;
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            ar.append_link(&mut header, "bbbb/layer.tar", "../aaaa/layer.tar")?;
+            append(&mut ar, "cccc.json", config)?;
+            append(
+                &mut ar,
+                "manifest.json",
+                br#"[{"Config":"cccc.json","RepoTags":["localhost:5000/test/docker:1"],"Layers":["aaaa/layer.tar","bbbb/layer.tar"]}]"#,
+            )?;
+            ar.finish()?;
+        }
+        assert!(DockerArchive::is_docker_archive(&path)?);
+        // as `docker save | gzip`
+        let gzipped = tmp_dir.path().join("docker.tar.gz");
+        Compression::default()
+            .compress_stream(&mut fs::File::open(&path)?, fs::File::create(&gzipped)?)?;
+        assert!(DockerArchive::is_docker_archive(&gzipped)?);
+        assert_eq!(
+            DockerArchive::new(&gzipped)?.get_manifest()?,
+            DockerArchive::new(&path)?.get_manifest()?
+        );
+
+        let mut archive = DockerArchive::new(&path)?;
+        assert_eq!(
+            archive.get_name()?,
+            ImageName::parse("localhost:5000/test/docker:1")?
+        );
+        let manifest = archive.get_manifest()?;
+        assert_eq!(manifest.config().media_type(), &MediaType::ImageConfig);
+        assert_eq!(manifest.layers().len(), 2);
+        let layer_digest = Digest::eval_sha256_digest(&layer);
+        for desc in manifest.layers() {
+            assert_eq!(desc.media_type(), &MediaType::ImageLayer);
+            assert_eq!(desc.digest(), &layer_digest);
+        }
+        assert_eq!(archive.get_blob(&layer_digest)?, layer);
+        assert_eq!(
+            archive.get_blob(manifest.config().digest())?,
+            config.to_vec()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let input = tmp_dir.path().join("input.txt");
+        fs::write(&input, "hello")?;
+        let name = ImageName::parse("localhost:5000/test/docker:1")?;
+        let mut b = crate::image::Builder::new(tmp_dir.path().join("oci.tar"), name.clone())?;
+        b.append_files(&[&input])?;
+        let mut oci = b.build()?;
+
+        let docker_path = tmp_dir.path().join("docker.tar");
+        let mut docker = copy(
+            &mut *oci,
+            DockerArchiveBuilder::new(docker_path.clone(), name.clone())?,
+        )?;
+        assert!(DockerArchive::is_docker_archive(&docker_path)?);
+        assert_eq!(docker.get_name()?, name);
+        let manifest = docker.get_manifest()?;
+        assert_eq!(manifest, oci.get_manifest()?);
+        assert_eq!(docker.get_manifest_digest()?, oci.get_manifest_digest()?);
+
+        let oci_path = tmp_dir.path().join("back.tar");
+        let mut back = copy(
+            &mut docker,
+            crate::image::OciArchiveBuilder::new(oci_path.clone(), name.clone())?,
+        )?;
+        assert_eq!(back.get_manifest()?, manifest);
+        assert!(!DockerArchive::is_docker_archive(&oci_path)?);
+        let _ = OciArchive::new(&oci_path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{
+    digest::DigestExt,
+    image::{Image, ImageBuilder},
+    ImageName,
+};
+use anyhow::{Context, Result};
+use oci_spec::image::{Digest, ImageManifest};
+use std::collections::HashMap;
+
+/// Build an [InMemory] image
+#[derive(Debug, Default)]
+pub struct InMemoryBuilder {
+    image_name: Option<ImageName>,
+    blobs: HashMap<Digest, Vec<u8>>,
+}
+
+impl InMemoryBuilder {
+    pub fn new_unnamed() -> Self {
+        Self::default()
+    }
+
+    pub fn new(image_name: ImageName) -> Self {
+        Self {
+            image_name: Some(image_name),
+            blobs: HashMap::new(),
+        }
+    }
+}
+
+impl ImageBuilder for InMemoryBuilder {
+    type Image = InMemory;
+
+    fn add_blob(&mut self, data: &[u8]) -> Result<(Digest, u64)> {
+        let digest = Digest::eval_sha256_digest(data);
+        self.blobs.insert(digest.clone(), data.to_vec());
+        Ok((digest, data.len() as u64))
+    }
+
+    fn build(self, manifest: ImageManifest) -> Result<Self::Image> {
+        Ok(InMemory {
+            image_name: self.image_name,
+            manifest,
+            blobs: self.blobs,
+        })
+    }
+}
+
+/// Image layout held in memory
+///
+/// This is useful for tests and short-lived tools which do not need to store the image on disk.
+/// Use [crate::image::copy] to convert it from or into other layouts, e.g. [crate::image::OciDir].
+#[derive(Debug, Clone)]
+pub struct InMemory {
+    image_name: Option<ImageName>,
+    manifest: ImageManifest,
+    blobs: HashMap<Digest, Vec<u8>>,
+}
+
+impl InMemory {
+    /// Blobs stored in this image, including those not referenced from the manifest
+    pub fn blobs(&self) -> &HashMap<Digest, Vec<u8>> {
+        &self.blobs
+    }
+}
+
+impl Image for InMemory {
+    fn get_name(&mut self) -> Result<ImageName> {
+        self.image_name.clone().context("Image name is not set")
+    }
+
+    fn get_blob(&mut self, digest: &Digest) -> Result<Vec<u8>> {
+        self.blobs
+            .get(digest)
+            .cloned()
+            .with_context(|| format!("Missing blob: {digest}"))
+    }
+
+    fn get_manifest(&mut self) -> Result<ImageManifest> {
+        Ok(self.manifest.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{copy, OciArchiveBuilder, OciArtifactBuilder, OciDirBuilder};
+    use oci_spec::image::MediaType;
+
+    fn artifact(image_name: &ImageName) -> Result<InMemory> {
+        let mut builder = OciArtifactBuilder::new(
+            InMemoryBuilder::new(image_name.clone()),
+            MediaType::Other("test".to_string()),
+        )?;
+        builder.add_layer(
+            MediaType::Other("layer".to_string()),
+            b"layer",
+            Default::default(),
+        )?;
+        let artifact = builder.build()?;
+        Ok((*artifact).clone())
+    }
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let image_name = ImageName::parse("test")?;
+        let mut image = artifact(&image_name)?;
+        let manifest = image.get_manifest()?;
+        assert_eq!(image.get_name()?, image_name);
+        assert_eq!(
+            image.get_blob(&Digest::eval_sha256_digest(b"layer"))?,
+            b"layer"
+        );
+        assert!(InMemoryBuilder::new_unnamed()
+            .build(manifest.clone())?
+            .get_name()
+            .is_err());
+
+        let tmp_dir = tempfile::tempdir()?;
+        let mut oci_dir = copy(
+            &mut image,
+            OciDirBuilder::new(tmp_dir.path().join("oci-dir"), image_name.clone())?,
+        )?;
+        let mut from_dir = copy(&mut oci_dir, InMemoryBuilder::new(image_name.clone()))?;
+        assert_eq!(from_dir.get_manifest()?, manifest);
+        assert_eq!(
+            from_dir.get_manifest_digest()?,
+            image.get_manifest_digest()?
+        );
+
+        let mut oci_archive = copy(
+            &mut image,
+            OciArchiveBuilder::new(tmp_dir.path().join("oci.tar"), image_name.clone())?,
+        )?;
+        let from_archive = copy(&mut oci_archive, InMemoryBuilder::new(image_name))?;
+        assert_eq!(from_archive.blobs(), image.blobs());
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{
-    image::{OciArchive, OciDir},
-    ImageName,
+    digest::DigestExt,
+    image::{reproducible::to_canonical_json, DockerArchive, OciArchive, OciDir},
+    local, ImageName,
 };
 
 #[cfg(feature = "remote")]
@@ -27,6 +28,35 @@ pub trait Image {
 
     /// The manifest of this image
     fn get_manifest(&mut self) -> Result<ImageManifest>;
+
+    /// Digest of the manifest of this image
+    ///
+    /// Default implementation evaluates the digest of [Image::get_manifest] serialized into canonical JSON,
+    /// which may differ from the digest of the original manifest.
+    fn get_manifest_digest(&mut self) -> Result<Digest> {
+        let manifest = self.get_manifest()?;
+        Ok(Digest::eval_sha256_digest(
+            to_canonical_json(&manifest)?.as_bytes(),
+        ))
+    }
+}
+
+impl<T: Image + ?Sized> Image for Box<T> {
+    fn get_name(&mut self) -> Result<ImageName> {
+        (**self).get_name()
+    }
+
+    fn get_blob(&mut self, digest: &Digest) -> Result<Vec<u8>> {
+        (**self).get_blob(digest)
+    }
+
+    fn get_manifest(&mut self) -> Result<ImageManifest> {
+        (**self).get_manifest()
+    }
+
+    fn get_manifest_digest(&mut self) -> Result<Digest> {
+        (**self).get_manifest_digest()
+    }
 }
 
 /// Build an [Image]
@@ -39,6 +69,14 @@ pub trait ImageBuilder {
     /// Add a blob to the image layout.
     fn add_blob(&mut self, data: &[u8]) -> Result<(Digest, u64)>;
 
+    /// Reuse a blob already available to this builder, e.g. in a shared blob store,
+    /// instead of fetching it from the source image.
+    ///
+    /// Returns `true` if the blob of `digest` and `size` has been added. Default implementation never reuses.
+    fn try_reuse_blob(&mut self, _digest: &Digest, _size: u64) -> Result<bool> {
+        Ok(false)
+    }
+
     /// Finish building image layout.
     fn build(self, manifest: ImageManifest) -> Result<Self::Image>;
 
@@ -54,48 +92,72 @@ pub trait ImageBuilder {
 }
 
 /// Copy image from one to another.
+///
+/// Blobs reusable by [ImageBuilder::try_reuse_blob] are not fetched from `from`.
 pub fn copy<From: Image, To: ImageBuilder>(from: &mut From, mut to: To) -> Result<To::Image> {
     let name = from.get_name()?;
     let manifest = from.get_manifest()?;
     for layer in manifest.layers() {
-        let digest = layer.digest();
-        let blob = from.get_blob(digest)?;
-        let (digest_new, size) = to.add_blob(&blob)?;
-        if digest != &digest_new {
-            bail!("Digest of a layer in {name} mismatch: {digest} != {digest_new}",);
-        }
-        if size != layer.size() {
-            bail!(
-                "Size of a layer in {name} mismatch: {size} != {}",
-                layer.size()
-            );
-        }
+        copy_blob(from, &mut to, layer, "layer", &name)?;
+    }
+    copy_blob(from, &mut to, manifest.config(), "config", &name)?;
+    to.build(manifest)
+}
+
+fn copy_blob<From: Image, To: ImageBuilder>(
+    from: &mut From,
+    to: &mut To,
+    desc: &Descriptor,
+    kind: &str,
+    name: &ImageName,
+) -> Result<()> {
+    let digest = desc.digest();
+    if to.try_reuse_blob(digest, desc.size())? {
+        log::info!("Reuse {kind} blob: {digest}");
+        return Ok(());
     }
-    let config = manifest.config();
-    let digest = config.digest();
     let blob = from.get_blob(digest)?;
     let (digest_new, size) = to.add_blob(&blob)?;
     if digest != &digest_new {
-        bail!("Digest of a config in {name} mismatch: {digest} != {digest_new}",);
+        bail!("Digest of a {kind} in {name} mismatch: {digest} != {digest_new}",);
     }
-    if size != config.size() {
+    if size != desc.size() {
         bail!(
-            "Size of a config in {name} mismatch: {size} != {}",
-            config.size()
+            "Size of a {kind} in {name} mismatch: {size} != {}",
+            desc.size()
         );
     }
-    to.build(manifest)
+    Ok(())
+}
+
+/// Open a tar archive of an image, either oci-archive or docker-archive
+pub fn read_archive(path: &Path) -> Result<Box<dyn Image>> {
+    if DockerArchive::is_docker_archive(path)? {
+        Ok(Box::new(DockerArchive::new(path)?))
+    } else {
+        Ok(Box::new(OciArchive::new(path)?))
+    }
 }
 
 pub fn read(name_or_path: &str) -> Result<Box<dyn Image>> {
     let path: &Path = name_or_path.as_ref();
     if path.is_file() {
-        return Ok(Box::new(OciArchive::new(path)?));
+        return read_archive(path);
     }
     if path.is_dir() {
         return Ok(Box::new(OciDir::new(path)?));
     }
 
+    if local::is_offline() {
+        let image_name = ImageName::parse(name_or_path)
+            .with_context(|| format!("Invalid image name or path: {name_or_path}"))?;
+        let dir = local::image_dir(&image_name)?;
+        if !dir.exists() {
+            local::ensure_online(&image_name, true)?;
+        }
+        return Ok(Box::new(OciDir::new(&dir.join(".oci-dir"))?));
+    }
+
     #[cfg(feature = "remote")]
     {
         if let Ok(image_name) = ImageName::parse(name_or_path) {
@@ -111,6 +173,15 @@ pub fn read(name_or_path: &str) -> Result<Box<dyn Image>> {
     );
 }
 
+pub(crate) fn get_manifest_digest_from_index(index: &ImageIndex) -> Result<Digest> {
+    Ok(index
+        .manifests()
+        .first()
+        .context("No manifest found in index.json")?
+        .digest()
+        .clone())
+}
+
 pub(crate) fn get_name_from_index(index: &ImageIndex) -> Result<ImageName> {
     if index.manifests().len() != 1 {
         bail!("Multiple manifests in a index.json, it is not allowed in ocipkg.");
@@ -3,19 +3,27 @@
 //! See the crate level documentation for more information.
 
 pub mod annotations;
+pub mod unpack;
 
 mod artifact;
+mod compression;
 mod config;
+mod docker_archive;
+mod in_memory;
 mod layout;
 mod oci_archive;
 mod oci_artifact;
 mod oci_dir;
 #[cfg(feature = "remote")]
 mod remote;
+pub(crate) mod reproducible;
 mod runnable;
 
 pub use artifact::*;
+pub use compression::Compression;
 pub use config::*;
+pub use docker_archive::*;
+pub use in_memory::*;
 pub use layout::*;
 pub use oci_archive::*;
 pub use oci_artifact::*;
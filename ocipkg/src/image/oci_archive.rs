@@ -1,24 +1,31 @@
 use crate::{
     digest::DigestExt,
-    image::{get_name_from_index, Image, ImageBuilder},
+    image::{
+        compression::open_tar, get_manifest_digest_from_index, get_name_from_index,
+        reproducible::*, Compression, Image, ImageBuilder,
+    },
     ImageName,
 };
 use anyhow::{bail, Context, Result};
-use chrono::Utc;
 use maplit::hashmap;
 use oci_spec::image::{
-    DescriptorBuilder, Digest, ImageIndex, ImageIndexBuilder, ImageManifest, MediaType,
+    Descriptor, DescriptorBuilder, Digest, ImageIndex, ImageIndexBuilder, ImageManifest, MediaType,
 };
 use std::{
+    collections::HashMap,
     fs,
-    io::{Read, Seek},
-    path::{Path, PathBuf},
+    io::{Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
 };
 
 /// Build an [OciArchive]
+///
+/// The archive is compressed as a whole if the path ends with `.tar.gz`, `.tgz`, `.tar.zst`, or `.tzst`,
+/// see [OciArchiveBuilder::set_compression] to set it explicitly.
 pub struct OciArchiveBuilder {
     image_name: Option<ImageName>,
     path: PathBuf,
+    compression: Compression,
     ar: tar::Builder<fs::File>,
 }
 
@@ -27,26 +34,30 @@ impl OciArchiveBuilder {
         if path.exists() {
             bail!("File already exists: {}", path.display());
         }
-        let f = fs::File::create(&path)?;
+        // Readable to compress the tar archive in `build`
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
         let ar = tar::Builder::new(f);
         Ok(Self {
             ar,
+            compression: Compression::from_extension(&path),
             path,
             image_name: None,
         })
     }
 
     pub fn new(path: PathBuf, image_name: ImageName) -> Result<Self> {
-        if path.exists() {
-            bail!("File already exists: {}", path.display());
-        }
-        let f = fs::File::create(&path)?;
-        let ar = tar::Builder::new(f);
-        Ok(Self {
-            ar,
-            path,
-            image_name: Some(image_name),
-        })
+        let mut builder = Self::new_unnamed(path)?;
+        builder.image_name = Some(image_name);
+        Ok(builder)
+    }
+
+    /// Set compression of the whole archive
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
     }
 }
 
@@ -56,12 +67,12 @@ impl ImageBuilder for OciArchiveBuilder {
     fn add_blob(&mut self, blob: &[u8]) -> Result<(Digest, u64)> {
         let digest = Digest::eval_sha256_digest(blob);
         self.ar
-            .append_data(&mut create_file_header(blob.len()), digest.as_path(), blob)?;
+            .append_data(&mut create_file_header(blob.len())?, digest.as_path(), blob)?;
         Ok((digest, blob.len() as u64))
     }
 
     fn build(mut self, manifest: ImageManifest) -> Result<Self::Image> {
-        let manifest_json = serde_json::to_string(&manifest)?;
+        let manifest_json = to_canonical_json(&manifest)?;
         let (digest, size) = self.add_blob(manifest_json.as_bytes())?;
         let descriptor = DescriptorBuilder::default()
             .media_type(MediaType::ImageManifest)
@@ -69,7 +80,7 @@ impl ImageBuilder for OciArchiveBuilder {
             .digest(digest)
             .annotations(if let Some(name) = &self.image_name {
                 hashmap! {
-                    "org.opencontainers.image.ref.name".to_string() => name.to_string()
+                    REF_NAME.to_string() => name.to_string()
                 }
             } else {
                 hashmap! {}
@@ -79,29 +90,49 @@ impl ImageBuilder for OciArchiveBuilder {
             .schema_version(2_u32)
             .manifests(vec![descriptor])
             .build()?;
-        let index_json = serde_json::to_string(&index)?;
+        let index_json = to_canonical_json(&index)?;
         let buf = index_json.as_bytes();
         self.ar
-            .append_data(&mut create_file_header(buf.len()), "index.json", buf)?;
+            .append_data(&mut create_file_header(buf.len())?, "index.json", buf)?;
 
-        self.ar.finish()?;
+        let mut f = self.ar.into_inner()?;
+        if self.compression != Compression::None {
+            // Compress into a temporary file in the same directory, and replace the tar with it
+            let dir = self.path.parent().unwrap_or(Path::new("."));
+            let tmp = tempfile::NamedTempFile::new_in(dir)?;
+            f.rewind()?;
+            self.compression.compress_stream(&mut f, tmp.as_file())?;
+            tmp.persist(&self.path)?;
+        }
         OciArchive::new(&self.path)
     }
 }
 
-fn create_file_header(size: usize) -> tar::Header {
+fn create_file_header(size: usize) -> Result<tar::Header> {
     let mut header = tar::Header::new_gnu();
     header.set_size(size as u64);
+    header.set_mode(0o644);
+    // Use fixed mtime for reproducible build
+    header.set_mtime(source_date_epoch()?);
     header.set_cksum();
-    header.set_mode(0b110100100); // rw-r--r--
-    header.set_mtime(Utc::now().timestamp() as u64);
-    header
+    Ok(header)
 }
 
 /// `oci-archive` image layout, a tar archive of [OCI Image Layout](https://github.com/opencontainers/image-spec/blob/v1.1.0/image-layout.md).
+///
+/// The archive may be compressed by gzip or zstd, which is detected by its magic bytes.
+/// The offsets of entries are indexed at opening, and blobs are read by seeking to them.
+///
+/// An archive may contain multiple manifests after [OciArchive::append].
+/// Use [OciArchive::select] to choose the manifest handled as [Image].
 pub struct OciArchive {
-    // Since `tar::Archive` does not have API to get mutable reference of inner part, we need to take it out and put it back.
-    ar: Option<tar::Archive<fs::File>>,
+    path: PathBuf,
+    compression: Compression,
+    // Decompressed tar archive
+    f: fs::File,
+    // Offset and size of the content of each regular file
+    entries: HashMap<PathBuf, (u64, u64)>,
+    selected: Option<ImageName>,
 }
 
 impl OciArchive {
@@ -109,65 +140,401 @@ impl OciArchive {
         if !path.is_file() {
             bail!("Not a file: {}", path.display());
         }
-        let f = fs::File::open(path)?;
-        let ar = tar::Archive::new(f);
-        Ok(Self { ar: Some(ar) })
+        let (f, compression) = open_tar(path)?;
+        let mut entries = HashMap::new();
+        for entry in tar::Archive::new(&f).entries_with_seek()? {
+            let entry = entry?;
+            if !matches!(
+                entry.header().entry_type(),
+                tar::EntryType::Regular | tar::EntryType::Continuous
+            ) {
+                continue;
+            }
+            // Archives created by `tar -C dir -cf out.tar .` have `./` prefix
+            let path: PathBuf = entry
+                .path()?
+                .components()
+                .filter(|c| c != &Component::CurDir)
+                .collect();
+            entries.insert(path, (entry.raw_file_position(), entry.size()));
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            compression,
+            f,
+            entries,
+            selected: None,
+        })
+    }
+
+    fn read_entry(&mut self, path: &Path) -> Result<Option<Vec<u8>>> {
+        let Some(&(offset, size)) = self.entries.get(path) else {
+            return Ok(None);
+        };
+        self.f.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; size as usize];
+        self.f.read_exact(&mut buf)?;
+        Ok(Some(buf))
     }
 
-    fn rewind(&mut self) -> Result<()> {
-        let ar = self.ar.take().unwrap();
-        let mut f = ar.into_inner();
-        f.rewind()?;
-        self.ar = Some(tar::Archive::new(f));
+    /// `index.json` of the archive
+    pub fn get_index(&mut self) -> Result<ImageIndex> {
+        let buf = self
+            .read_entry(Path::new("index.json"))?
+            .context("Missing index.json")?;
+        Ok(ImageIndex::from_reader(buf.as_slice())?)
+    }
+
+    /// Handle the manifest of `image_name` as [Image]
+    pub fn select(&mut self, image_name: &ImageName) -> Result<()> {
+        find_manifest(&self.get_index()?, image_name)?;
+        self.selected = Some(image_name.clone());
         Ok(())
     }
 
-    fn get_entries(&mut self) -> Result<impl Iterator<Item = tar::Entry<'_, fs::File>>> {
-        self.rewind()?;
-        Ok(self
-            .ar
-            .as_mut()
-            .unwrap()
-            .entries_with_seek()?
-            .filter_map(|e| e.ok()))
+    /// Position of the manifest handled as [Image] in `index.json`
+    fn target(&self, index: &ImageIndex) -> Result<usize> {
+        match &self.selected {
+            Some(name) => find_manifest(index, name),
+            None => match index.manifests().len() {
+                0 => bail!("No manifest found in index.json"),
+                1 => Ok(0),
+                _ => bail!(
+                    "Multiple manifests in {}, select one of them",
+                    self.path.display()
+                ),
+            },
+        }
     }
 
-    fn get_index(&mut self) -> Result<ImageIndex> {
-        for entry in self.get_entries()? {
-            let path = entry.path()?;
-            if path == Path::new("index.json") {
-                return Ok(ImageIndex::from_reader(entry)?);
+    /// Rewrite `org.opencontainers.image.ref.name` of the manifest into `image_name`
+    pub fn retag(&mut self, image_name: &ImageName) -> Result<()> {
+        let mut index = self.get_index()?;
+        let target = self.target(&index)?;
+        if let Ok(found) = find_manifest(&index, image_name) {
+            if found != target {
+                bail!("{image_name} already exists in {}", self.path.display());
             }
         }
-        bail!("Missing index.json")
+        let mut manifests = index.manifests().clone();
+        let mut annotations = manifests[target].annotations().clone().unwrap_or_default();
+        annotations.insert(REF_NAME.to_string(), image_name.to_string());
+        manifests[target].set_annotations(Some(annotations));
+        index.set_manifests(manifests);
+        self.rewrite(&index, Vec::new(), &[])?;
+        if self.selected.is_some() {
+            self.selected = Some(image_name.clone());
+        }
+        Ok(())
     }
+
+    /// Append the manifest of another image with its blobs
+    ///
+    /// Blobs already in this archive are reused.
+    pub fn append(&mut self, image: &mut impl Image) -> Result<()> {
+        let image_name = image.get_name()?;
+        let mut index = self.get_index()?;
+        if find_manifest(&index, &image_name).is_ok() {
+            bail!("{image_name} already exists in {}", self.path.display());
+        }
+        let manifest = image.get_manifest()?;
+        let mut blobs = Vec::new();
+        for desc in manifest.layers().iter().chain([manifest.config()]) {
+            let digest = desc.digest();
+            if self.entries.contains_key(&digest.as_path()) {
+                continue;
+            }
+            let blob = image.get_blob(digest)?;
+            if &Digest::eval_sha256_digest(&blob) != digest {
+                bail!("Digest of a blob in {image_name} mismatch: {digest}");
+            }
+            blobs.push(blob);
+        }
+        let manifest_json = to_canonical_json(&manifest)?;
+        let descriptor = DescriptorBuilder::default()
+            .media_type(MediaType::ImageManifest)
+            .size(manifest_json.len() as u64)
+            .digest(Digest::eval_sha256_digest(manifest_json.as_bytes()))
+            .annotations(hashmap! { REF_NAME.to_string() => image_name.to_string() })
+            .build()?;
+        blobs.push(manifest_json.into_bytes());
+        let mut manifests = index.manifests().clone();
+        manifests.push(descriptor);
+        index.set_manifests(manifests);
+        self.rewrite(&index, blobs, &[])
+    }
+
+    /// Remove the manifest of `image_name`, and blobs not referenced from other manifests
+    pub fn remove(&mut self, image_name: &ImageName) -> Result<()> {
+        let mut index = self.get_index()?;
+        let target = find_manifest(&index, image_name)?;
+        if index.manifests().len() == 1 {
+            bail!("Cannot remove the last manifest in {}", self.path.display());
+        }
+        let mut manifests = index.manifests().clone();
+        let removed = manifests.remove(target);
+        index.set_manifests(manifests);
+
+        let mut unused = self.referenced(&removed)?;
+        for desc in index.manifests() {
+            for digest in self.referenced(desc)? {
+                unused.retain(|d| d != &digest);
+            }
+        }
+        let unused: Vec<PathBuf> = unused.iter().map(|d| d.as_path()).collect();
+        self.rewrite(&index, Vec::new(), &unused)?;
+        if self.selected.as_ref() == Some(image_name) {
+            self.selected = None;
+        }
+        Ok(())
+    }
+
+    /// Digests of the manifest and blobs referenced from it
+    fn referenced(&mut self, desc: &Descriptor) -> Result<Vec<Digest>> {
+        let mut digests = vec![desc.digest().clone()];
+        if desc.media_type() == &MediaType::ImageManifest {
+            if let Some(buf) = self.read_entry(&desc.digest().as_path())? {
+                let manifest: ImageManifest = serde_json::from_slice(&buf)?;
+                digests.push(manifest.config().digest().clone());
+                digests.extend(manifest.layers().iter().map(|l| l.digest().clone()));
+            }
+        }
+        Ok(digests)
+    }
+
+    /// Rewrite the archive with new `index.json`, reusing existing entries except `removed`
+    fn rewrite(
+        &mut self,
+        index: &ImageIndex,
+        blobs: Vec<Vec<u8>>,
+        removed: &[PathBuf],
+    ) -> Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let tmp = tempfile::NamedTempFile::new_in(dir)?;
+        let f = if self.compression == Compression::None {
+            tmp.reopen()?
+        } else {
+            tempfile::tempfile()?
+        };
+        let mut ar = tar::Builder::new(f);
+
+        // Keep the order of entries in the original archive
+        let mut entries: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(path, _)| {
+                path.as_path() != Path::new("index.json") && !removed.contains(path)
+            })
+            .map(|(path, (offset, size))| (*offset, *size, path.clone()))
+            .collect();
+        entries.sort();
+        for (offset, size, path) in entries {
+            self.f.seek(SeekFrom::Start(offset))?;
+            ar.append_data(
+                &mut create_file_header(size as usize)?,
+                path,
+                (&mut self.f).take(size),
+            )?;
+        }
+        for blob in blobs {
+            let digest = Digest::eval_sha256_digest(&blob);
+            if !self.entries.contains_key(&digest.as_path()) {
+                ar.append_data(
+                    &mut create_file_header(blob.len())?,
+                    digest.as_path(),
+                    blob.as_slice(),
+                )?;
+            }
+        }
+        let index_json = to_canonical_json(index)?;
+        ar.append_data(
+            &mut create_file_header(index_json.len())?,
+            "index.json",
+            index_json.as_bytes(),
+        )?;
+
+        let mut f = ar.into_inner()?;
+        if self.compression != Compression::None {
+            f.rewind()?;
+            self.compression.compress_stream(&mut f, tmp.as_file())?;
+        }
+        tmp.persist(&self.path)?;
+
+        let selected = self.selected.take();
+        *self = Self::new(&self.path)?;
+        self.selected = selected;
+        Ok(())
+    }
+}
+
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// Position of the manifest of `image_name` in `index.json`
+fn find_manifest(index: &ImageIndex, image_name: &ImageName) -> Result<usize> {
+    index
+        .manifests()
+        .iter()
+        .position(|desc| {
+            desc.annotations()
+                .as_ref()
+                .and_then(|annotations| annotations.get(REF_NAME))
+                .and_then(|name| ImageName::parse(name).ok())
+                .as_ref()
+                == Some(image_name)
+        })
+        .with_context(|| format!("{image_name} is not found in index.json"))
 }
 
 impl Image for OciArchive {
     fn get_name(&mut self) -> Result<ImageName> {
-        get_name_from_index(&self.get_index()?)
+        match &self.selected {
+            Some(name) => Ok(name.clone()),
+            None => get_name_from_index(&self.get_index()?),
+        }
     }
 
     fn get_blob(&mut self, digest: &Digest) -> Result<Vec<u8>> {
-        for mut entry in self.get_entries()? {
-            let path = entry.path()?;
-            if path == digest.as_path() {
-                let mut buf = Vec::new();
-                entry.read_to_end(&mut buf)?;
-                return Ok(buf);
-            }
-        }
-        bail!("Missing blob: {}", digest)
+        self.read_entry(&digest.as_path())?
+            .with_context(|| format!("Missing blob: {}", digest))
     }
 
     fn get_manifest(&mut self) -> Result<ImageManifest> {
         let index = self.get_index()?;
-        let desc = index
-            .manifests()
-            .first()
-            .context("No manifest found in index.json")?;
-        let digest = desc.digest();
-        let manifest = serde_json::from_slice(self.get_blob(digest)?.as_slice())?;
+        let digest = index.manifests()[self.target(&index)?].digest().clone();
+        let manifest = serde_json::from_slice(self.get_blob(&digest)?.as_slice())?;
         Ok(manifest)
     }
+
+    fn get_manifest_digest(&mut self) -> Result<Digest> {
+        let index = self.get_index()?;
+        match &self.selected {
+            Some(name) => Ok(index.manifests()[find_manifest(&index, name)?]
+                .digest()
+                .clone()),
+            None => get_manifest_digest_from_index(&index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Builder;
+
+    #[test]
+    fn compressed() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let input = tmp_dir.path().join("input.txt");
+        fs::write(&input, "hello")?;
+        let name = ImageName::parse("localhost:5000/test/compressed:1")?;
+
+        let mut manifests = Vec::new();
+        for (file_name, magic) in [
+            ("a.tar", &[] as &[u8]),
+            ("a.tar.gz", &[0x1f, 0x8b]),
+            ("a.tar.zst", &[0x28, 0xb5, 0x2f, 0xfd]),
+        ] {
+            let path = tmp_dir.path().join(file_name);
+            let mut b = Builder::new(path.clone(), name.clone())?;
+            b.append_files(&[&input])?;
+            b.build()?;
+            assert!(fs::read(&path)?.starts_with(magic));
+
+            let mut ar = OciArchive::new(&path)?;
+            assert_eq!(ar.get_name()?, name);
+            let manifest = ar.get_manifest()?;
+            for layer in manifest.layers() {
+                assert_eq!(ar.get_blob(layer.digest())?.len() as u64, layer.size());
+            }
+            manifests.push(manifest);
+        }
+        assert_eq!(manifests[0], manifests[1]);
+        assert_eq!(manifests[0], manifests[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn current_dir_prefix() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let input = tmp_dir.path().join("input.txt");
+        fs::write(&input, "hello")?;
+        let name = ImageName::parse("localhost:5000/test/prefix:1")?;
+        let original = tmp_dir.path().join("original.tar");
+        let mut b = Builder::new(original.clone(), name.clone())?;
+        b.append_files(&[&input])?;
+        let manifest = b.build()?.get_manifest()?;
+
+        // Re-archive with `./` prefix as `tar -C dir -cf out.tar .` does
+        let path = tmp_dir.path().join("prefixed.tar");
+        let mut ar = tar::Builder::new(fs::File::create(&path)?);
+        for entry in tar::Archive::new(fs::File::open(&original)?).entries()? {
+            let mut entry = entry?;
+            let name = Path::new(".").join(entry.path()?);
+            let mut header = entry.header().clone();
+            ar.append_data(&mut header, name, &mut entry)?;
+        }
+        ar.finish()?;
+
+        let mut ar = OciArchive::new(&path)?;
+        assert_eq!(ar.get_name()?, name);
+        assert_eq!(ar.get_manifest()?, manifest);
+        Ok(())
+    }
+
+    fn build(path: PathBuf, name: &ImageName, content: &str) -> Result<OciArchive> {
+        let input = path.with_extension("txt");
+        fs::write(&input, content)?;
+        let mut b = Builder::new(path.clone(), name.clone())?;
+        b.append_files(&[&input])?;
+        b.build()?;
+        OciArchive::new(&path)
+    }
+
+    #[test]
+    fn modify() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let a = ImageName::parse("localhost:5000/test/a:1")?;
+        let b = ImageName::parse("localhost:5000/test/b:1")?;
+        let c = ImageName::parse("localhost:5000/test/c:1")?;
+
+        for file_name in ["a.tar", "a.tar.gz"] {
+            let path = tmp_dir.path().join(file_name);
+            let mut ar = build(path.clone(), &a, "a")?;
+            let manifest_a = ar.get_manifest()?;
+
+            ar.retag(&b)?;
+            let mut ar = OciArchive::new(&path)?;
+            assert_eq!(ar.get_name()?, b);
+            assert_eq!(ar.get_manifest()?, manifest_a);
+            assert_eq!(ar.compression, Compression::from_extension(&path));
+
+            let mut other = build(tmp_dir.path().join(format!("c-{file_name}")), &c, "c")?;
+            let manifest_c = other.get_manifest()?;
+            ar.append(&mut other)?;
+            assert!(ar.get_name().is_err());
+            assert!(ar.append(&mut other).is_err());
+            ar.select(&c)?;
+            assert_eq!(ar.get_name()?, c);
+            assert_eq!(ar.get_manifest()?, manifest_c);
+            for layer in manifest_c.layers() {
+                ar.get_blob(layer.digest())?;
+            }
+            ar.select(&b)?;
+            assert_eq!(ar.get_manifest()?, manifest_a);
+            assert!(ar.retag(&c).is_err());
+
+            ar.remove(&c)?;
+            let mut ar = OciArchive::new(&path)?;
+            assert_eq!(ar.get_name()?, b);
+            assert_eq!(ar.get_manifest()?, manifest_a);
+            for layer in manifest_c.layers() {
+                assert!(ar.get_blob(layer.digest()).is_err());
+            }
+            assert!(ar.remove(&b).is_err());
+        }
+        Ok(())
+    }
 }
@@ -1,4 +1,8 @@
-use crate::image::{Image, ImageBuilder, OciArchive, OciDir};
+use crate::{
+    encryption::{self, PublicKey, ENC_KEYS_ANNOTATION},
+    image::{Image, ImageBuilder, OciArchive, OciDir},
+    media_types,
+};
 
 #[cfg(feature = "remote")]
 use crate::{image::Remote, ImageName};
@@ -73,6 +77,22 @@ impl<LayoutBuilder: ImageBuilder> OciArtifactBuilder<LayoutBuilder> {
         Ok(layer)
     }
 
+    /// Append an encrypted `layer` to the OCI Artifact
+    ///
+    /// The blob is encrypted for the `recipients`, and `+encrypted` suffix is added to the media type.
+    /// See [crate::encryption] for detail.
+    pub fn add_encrypted_layer(
+        &mut self,
+        layer_type: MediaType,
+        layer_blob: &[u8],
+        recipients: &[PublicKey],
+        mut annotations: HashMap<String, String>,
+    ) -> Result<Descriptor> {
+        let (encrypted, keys) = encryption::encrypt(layer_blob, recipients)?;
+        annotations.insert(ENC_KEYS_ANNOTATION.to_string(), keys);
+        self.add_layer(media_types::encrypted(&layer_type), &encrypted, annotations)
+    }
+
     /// Add any type of annotation to the manifest of the OCI Artifact
     pub fn add_annotation(&mut self, key: String, value: String) {
         self.manifest
@@ -1,24 +1,28 @@
 use crate::{
     digest::DigestExt,
-    image::{Image, ImageBuilder},
+    image::{reproducible::to_canonical_json, Image, ImageBuilder},
     ImageName,
 };
 use anyhow::{bail, Context, Result};
-use maplit::hashmap;
 use oci_spec::image::{
-    DescriptorBuilder, Digest, ImageIndex, ImageIndexBuilder, ImageManifest, MediaType, OciLayout,
+    Descriptor, DescriptorBuilder, Digest, DigestAlgorithm, ImageIndex, ImageIndexBuilder,
+    ImageManifest, MediaType, OciLayout,
 };
 use std::{
+    collections::HashMap,
     fs,
+    io::Write,
     path::{Path, PathBuf},
 };
 
-use super::get_name_from_index;
+use super::{get_manifest_digest_from_index, get_name_from_index};
 
 /// Build an [OciDir]
 pub struct OciDirBuilder {
     image_name: Option<ImageName>,
     oci_dir_root: PathBuf,
+    blob_store: Option<PathBuf>,
+    index_annotations: HashMap<String, String>,
     is_finished: bool,
 }
 
@@ -46,6 +50,8 @@ impl OciDirBuilder {
         Ok(Self {
             image_name: None,
             oci_dir_root,
+            blob_store: None,
+            index_annotations: HashMap::new(),
             is_finished: false,
         })
     }
@@ -58,9 +64,67 @@ impl OciDirBuilder {
         Ok(Self {
             image_name: Some(image_name),
             oci_dir_root,
+            blob_store: None,
+            index_annotations: HashMap::new(),
             is_finished: false,
         })
     }
+
+    /// Store blobs in a content-addressed directory shared with other oci-dirs,
+    /// e.g. [crate::local::blob_store_dir], and hardlink them into this oci-dir.
+    ///
+    /// Blobs are copied if hardlink is not available, e.g. across filesystems.
+    pub fn with_blob_store(mut self, blob_store: PathBuf) -> Self {
+        self.blob_store = Some(blob_store);
+        self
+    }
+
+    /// Add an annotation to the manifest descriptor in `index.json`
+    pub fn with_index_annotation(mut self, key: &str, value: &str) -> Self {
+        self.index_annotations
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+}
+
+/// Advisory lock of a shared blob store, released when the returned file is dropped
+///
+/// Builders take the shared side while writing and hardlinking blobs,
+/// and [crate::local::prune] takes the exclusive side not to remove blobs being linked.
+pub(crate) fn lock_blob_store(store: &Path, exclusive: bool) -> Result<fs::File> {
+    fs::create_dir_all(store)?;
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(store.join(".lock"))?;
+    if exclusive {
+        file.lock()?;
+    } else {
+        file.lock_shared()?;
+    }
+    Ok(file)
+}
+
+/// Write a blob into the shared store atomically, since other processes may read it concurrently
+fn store_blob(stored: &Path, data: &[u8]) -> Result<()> {
+    let parent = stored.parent().unwrap();
+    fs::create_dir_all(parent)?;
+    let mut tmp = tempfile::NamedTempFile::new_in(parent)?;
+    tmp.write_all(data)?;
+    tmp.persist(stored)?;
+    Ok(())
+}
+
+fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if dest.exists() {
+        return Ok(());
+    }
+    if let Err(e) = fs::hard_link(src, dest) {
+        log::debug!("Hardlink failed, fallback to copy: {e}");
+        fs::copy(src, dest)?;
+    }
+    Ok(())
 }
 
 impl ImageBuilder for OciDirBuilder {
@@ -70,24 +134,55 @@ impl ImageBuilder for OciDirBuilder {
         let digest = Digest::eval_sha256_digest(data);
         let out = self.oci_dir_root.join(digest.as_path());
         fs::create_dir_all(out.parent().unwrap())?;
-        fs::write(out, data)?;
+        if let Some(store) = &self.blob_store {
+            let _lock = lock_blob_store(store, false)?;
+            let stored = store.join(digest.as_path());
+            if !stored.exists() {
+                store_blob(&stored, data)?;
+            }
+            link_or_copy(&stored, &out)?;
+        } else {
+            fs::write(out, data)?;
+        }
         Ok((digest, data.len() as u64))
     }
 
+    fn try_reuse_blob(&mut self, digest: &Digest, size: u64) -> Result<bool> {
+        let Some(store) = &self.blob_store else {
+            return Ok(false);
+        };
+        let _lock = lock_blob_store(store, false)?;
+        let stored = store.join(digest.as_path());
+        match fs::metadata(&stored) {
+            Ok(meta) if meta.len() == size => {}
+            _ => return Ok(false),
+        }
+        if &Digest::eval_sha256_digest(&fs::read(&stored)?) != digest {
+            log::warn!("Remove corrupted blob in store: {}", stored.display());
+            fs::remove_file(&stored)?;
+            return Ok(false);
+        }
+        let out = self.oci_dir_root.join(digest.as_path());
+        fs::create_dir_all(out.parent().unwrap())?;
+        link_or_copy(&stored, &out)?;
+        Ok(true)
+    }
+
     fn build(mut self, manifest: ImageManifest) -> Result<OciDir> {
-        let manifest_json = serde_json::to_string(&manifest)?;
+        let manifest_json = to_canonical_json(&manifest)?;
         let (digest, size) = self.add_blob(manifest_json.as_bytes())?;
+        let mut annotations = std::mem::take(&mut self.index_annotations);
+        if let Some(name) = &self.image_name {
+            annotations.insert(
+                "org.opencontainers.image.ref.name".to_string(),
+                name.to_string(),
+            );
+        }
         let descriptor = DescriptorBuilder::default()
             .media_type(MediaType::ImageManifest)
             .size(size)
             .digest(digest)
-            .annotations(if let Some(name) = &self.image_name {
-                hashmap! {
-                    "org.opencontainers.image.ref.name".to_string() => name.to_string()
-                }
-            } else {
-                hashmap! {}
-            })
+            .annotations(annotations)
             .build()?;
         let index = ImageIndexBuilder::default()
             .schema_version(2_u32)
@@ -99,7 +194,7 @@ impl ImageBuilder for OciDirBuilder {
         )?;
         fs::write(
             self.oci_dir_root.join("index.json"),
-            serde_json::to_string(&index)?,
+            to_canonical_json(&index)?,
         )?;
         self.is_finished = true;
         Ok(OciDir {
@@ -139,6 +234,56 @@ impl OciDir {
         let index_json = fs::read_to_string(index_path)?;
         Ok(serde_json::from_str(&index_json)?)
     }
+
+    /// Check consistency of `index.json`, manifest, config and layers,
+    /// and that every blob referred from them matches its digest and size.
+    pub fn verify(&mut self) -> Result<()> {
+        let index = self
+            .get_index()
+            .context("Failed to read index.json of oci-dir")?;
+        if index.manifests().is_empty() {
+            bail!("No manifest found in index.json");
+        }
+        for manifest_desc in index.manifests() {
+            if manifest_desc.media_type() != &MediaType::ImageManifest {
+                bail!(
+                    "Unsupported media type of manifest {}: {}",
+                    manifest_desc.digest(),
+                    manifest_desc.media_type()
+                );
+            }
+            let blob = self.verify_blob(manifest_desc)?;
+            let manifest: ImageManifest = serde_json::from_slice(&blob)
+                .with_context(|| format!("Invalid manifest: {}", manifest_desc.digest()))?;
+            self.verify_blob(manifest.config())?;
+            for layer in manifest.layers() {
+                self.verify_blob(layer)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_blob(&self, desc: &Descriptor) -> Result<Vec<u8>> {
+        let digest = desc.digest();
+        let blob = fs::read(self.oci_dir_root.join(digest.as_path()))
+            .with_context(|| format!("Blob not found: {digest}"))?;
+        if blob.len() as u64 != desc.size() {
+            bail!(
+                "Size mismatch of blob {digest}: expected {}, actual {}",
+                desc.size(),
+                blob.len()
+            );
+        }
+        if digest.algorithm() != &DigestAlgorithm::Sha256 {
+            log::warn!("Skip unsupported digest algorithm: {digest}");
+            return Ok(blob);
+        }
+        let actual = Digest::eval_sha256_digest(&blob);
+        if &actual != digest {
+            bail!("Digest mismatch of blob {digest}: actual {actual}");
+        }
+        Ok(blob)
+    }
 }
 
 impl Image for OciDir {
@@ -160,12 +305,16 @@ impl Image for OciDir {
         let manifest = serde_json::from_slice(self.get_blob(digest)?.as_slice())?;
         Ok(manifest)
     }
+
+    fn get_manifest_digest(&mut self) -> Result<Digest> {
+        get_manifest_digest_from_index(&self.get_index()?)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::image::OciArtifactBuilder;
+    use crate::image::{OciArtifact, OciArtifactBuilder};
 
     #[test]
     fn test_artifact_over_oci_dir() -> Result<()> {
@@ -190,4 +339,66 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn verify() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("oci-dir");
+        let oci_dir = OciDirBuilder::new(path.clone(), ImageName::parse("test")?)?;
+        let mut builder = OciArtifactBuilder::new(oci_dir, MediaType::Other("test".to_string()))?;
+        builder.add_layer(
+            MediaType::Other("layer".to_string()),
+            b"layer",
+            Default::default(),
+        )?;
+        let mut oci_dir = builder.build()?;
+        oci_dir.verify()?;
+
+        let blob = path.join(Digest::eval_sha256_digest(b"layer").as_path());
+        fs::write(&blob, b"LAYER")?;
+        assert!(oci_dir.verify().is_err());
+        fs::remove_file(&blob)?;
+        assert!(oci_dir.verify().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn shared_blob_store() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let store = tmp_dir.path().join("store");
+        let build = |dir: &str, tag: &str| -> Result<OciArtifact<OciDir>> {
+            let oci_dir = OciDirBuilder::new(
+                tmp_dir.path().join(dir),
+                ImageName::parse(&format!("test:{tag}"))?,
+            )?
+            .with_blob_store(store.clone());
+            let mut builder =
+                OciArtifactBuilder::new(oci_dir, MediaType::Other("test".to_string()))?;
+            builder.add_layer(
+                MediaType::Other("layer".to_string()),
+                b"shared",
+                Default::default(),
+            )?;
+            builder.build()
+        };
+        let mut a = build("a", "1")?;
+        let mut b = build("b", "2")?;
+        let digest = Digest::eval_sha256_digest(b"shared");
+        assert_eq!(a.get_blob(&digest)?, b"shared");
+        assert_eq!(b.get_blob(&digest)?, b"shared");
+        assert!(store.join(digest.as_path()).is_file());
+
+        let mut c = OciDirBuilder::new(tmp_dir.path().join("c"), ImageName::parse("test:3")?)?
+            .with_blob_store(store.clone());
+        assert!(c.try_reuse_blob(&digest, 6)?);
+        assert!(!c.try_reuse_blob(&Digest::eval_sha256_digest(b"missing"), 7)?);
+
+        // Builders cannot write into the store while it is locked exclusively
+        let lock = lock_blob_store(&store, true)?;
+        let other = fs::File::open(store.join(".lock"))?;
+        assert!(other.try_lock_shared().is_err());
+        drop(lock);
+        assert!(other.try_lock_shared().is_ok());
+        Ok(())
+    }
 }
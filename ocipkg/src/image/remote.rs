@@ -1,26 +1,55 @@
 use crate::{
     distribution::{Client, StoredAuth},
     image::{Image, ImageBuilder},
-    ImageName,
+    ImageName, Reference,
 };
 use anyhow::Result;
 use oci_spec::image::{Digest, ImageManifest};
 
 /// An image stored in remote registry as [Image]
+///
+/// The manifest is fetched once and cached, so that [Image::get_manifest] and [Image::get_manifest_digest]
+/// are consistent even if the tag is moved in the registry meanwhile.
 pub struct Remote {
     image_name: ImageName,
+    /// Reference used to fetch the manifest, the reference of `image_name` unless [Remote::with_digest] is used
+    reference: Reference,
     client: Client,
+    manifest: Option<(ImageManifest, Digest)>,
 }
 
 impl Remote {
     pub fn new(image_name: ImageName) -> Result<Self> {
         let client = Client::from_image_name(&image_name)?;
-        Ok(Self { image_name, client })
+        Ok(Self::from_client(image_name, client))
     }
 
     pub fn new_with_auth(image_name: ImageName, auth: StoredAuth) -> Result<Self> {
         let client = Client::from_image_name_with_auth(&image_name, auth)?;
-        Ok(Self { image_name, client })
+        Ok(Self::from_client(image_name, client))
+    }
+
+    fn from_client(image_name: ImageName, client: Client) -> Self {
+        Self {
+            reference: image_name.reference.clone(),
+            image_name,
+            client,
+            manifest: None,
+        }
+    }
+
+    /// Fetch the manifest by the digest instead of the tag, while the image is still named by the tag
+    pub fn with_digest(mut self, digest: &Digest) -> Result<Self> {
+        self.reference = Reference::new(digest.as_ref())?;
+        self.manifest = None;
+        Ok(self)
+    }
+
+    fn fetch_manifest(&mut self) -> Result<&(ImageManifest, Digest)> {
+        if self.manifest.is_none() {
+            self.manifest = Some(self.client.get_manifest_with_digest(&self.reference)?);
+        }
+        Ok(self.manifest.as_ref().unwrap())
     }
 
     pub fn add_basic_auth(&mut self, domain: &str, username: &str, password: &str) {
@@ -38,7 +67,12 @@ impl Image for Remote {
     }
 
     fn get_manifest(&mut self) -> Result<ImageManifest> {
-        self.client.get_manifest(&self.image_name.reference)
+        Ok(self.fetch_manifest()?.0.clone())
+    }
+
+    /// Digest of the manifest bytes actually fetched, see [Remote]
+    fn get_manifest_digest(&mut self) -> Result<Digest> {
+        Ok(self.fetch_manifest()?.1.clone())
     }
 }
 
@@ -72,12 +106,14 @@ impl ImageBuilder for RemoteBuilder {
         Ok((digest, data.len() as u64))
     }
 
+    /// Skip pushing the blob if it already exists in the repository
+    fn try_reuse_blob(&mut self, digest: &Digest, _size: u64) -> Result<bool> {
+        self.client.has_blob(digest)
+    }
+
     fn build(self, manifest: ImageManifest) -> Result<Self::Image> {
         self.client
             .push_manifest(&self.image_name.reference, &manifest)?;
-        Ok(Remote {
-            image_name: self.image_name,
-            client: self.client,
-        })
+        Ok(Remote::from_client(self.image_name, self.client))
     }
 }
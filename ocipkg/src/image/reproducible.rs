@@ -0,0 +1,70 @@
+//! Helpers for reproducible, byte-identical image builds
+//!
+//! See [reproducible-builds.org](https://reproducible-builds.org/docs/source-date-epoch/) for `SOURCE_DATE_EPOCH`.
+
+use crate::{
+    digest::DigestExt,
+    image::{FileEntry, FileType},
+};
+use anyhow::{Context, Result};
+use oci_spec::image::Digest;
+use serde::Serialize;
+use std::{fs, io, path::Path};
+
+/// Timestamp used as mtime of entries in tar archives
+///
+/// This is taken from `SOURCE_DATE_EPOCH` environment variable, or zero if it is not set.
+pub(crate) fn source_date_epoch() -> Result<u64> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid SOURCE_DATE_EPOCH: {epoch}")),
+        Err(_) => Ok(0),
+    }
+}
+
+/// Serialize into JSON with sorted object keys
+///
+/// Types in `oci_spec` store annotations in `HashMap`, and thus `serde_json::to_string` yields
+/// different bytes, i.e. different digests, for the same content.
+pub(crate) fn to_canonical_json<T: Serialize>(value: &T) -> Result<String> {
+    // `serde_json::Map` is a `BTreeMap` since `preserve_order` feature is not enabled.
+    Ok(serde_json::to_string(&serde_json::to_value(value)?)?)
+}
+
+/// Append a file, directory, or symbolic link to a tar archive with normalized metadata
+///
+/// Owner is set to root, permission is normalized to `0o644` or `0o755`,
+/// and mtime is set to [source_date_epoch].
+/// Returns the entry to be recorded in [crate::image::Config].
+pub(crate) fn append_path<W: io::Write>(
+    ar: &mut tar::Builder<W>,
+    name: &Path,
+    path: &Path,
+) -> Result<FileEntry> {
+    let meta = fs::symlink_metadata(path)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(&meta, tar::HeaderMode::Deterministic);
+    header.set_mtime(source_date_epoch()?);
+    let mut entry = FileEntry {
+        mode: Some(header.mode()?),
+        ..FileEntry::from_path(name.to_owned())
+    };
+    if meta.is_symlink() {
+        let target = fs::read_link(path)?;
+        ar.append_link(&mut header, name, &target)?;
+        entry.file_type = Some(FileType::Symlink);
+        entry.target = Some(target);
+    } else if meta.is_dir() {
+        ar.append_data(&mut header, name, io::empty())?;
+        entry.file_type = Some(FileType::Directory);
+    } else {
+        let buf = fs::read(path)?;
+        ar.append_data(&mut header, name, buf.as_slice())?;
+        entry.file_type = Some(FileType::File);
+        entry.size = Some(buf.len() as u64);
+        entry.digest = Some(Digest::eval_sha256_digest(&buf));
+    }
+    Ok(entry)
+}
@@ -1,7 +1,10 @@
 //! Executable container
 
 use super::OciArchiveBuilder;
-use crate::{image::ImageBuilder, ImageName};
+use crate::{
+    image::{reproducible::*, ImageBuilder},
+    ImageName,
+};
 use anyhow::{bail, Context, Result};
 use goblin::elf::Elf;
 use oci_spec::image::{
@@ -37,8 +40,7 @@ impl<LayoutBuilder: ImageBuilder> RunnableBuilder<LayoutBuilder> {
         let mut buf = Vec::new();
         {
             let mut tar_builder = tar::Builder::new(&mut buf);
-            let mut file = std::fs::File::open(path)?;
-            tar_builder.append_file(filename, &mut file)?;
+            append_path(&mut tar_builder, filename.as_ref(), path)?;
         }
 
         let (digest, size) = self.layout.add_blob(&buf)?;
@@ -70,9 +72,7 @@ impl<LayoutBuilder: ImageBuilder> RunnableBuilder<LayoutBuilder> {
                     .build()?,
             )
             .build()?;
-        let (digest, size) = self
-            .layout
-            .add_blob(serde_json::to_string(&cfg)?.as_bytes())?;
+        let (digest, size) = self.layout.add_blob(to_canonical_json(&cfg)?.as_bytes())?;
         let cfg_desc = DescriptorBuilder::default()
             .media_type(oci_spec::image::MediaType::ImageConfig)
             .size(size)
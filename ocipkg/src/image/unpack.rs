@@ -0,0 +1,392 @@
+//! Safe unpacking of layers into local filesystem
+//!
+//! Artifacts may be pulled from untrusted registries, and thus entries of layers are checked
+//! before they are written:
+//!
+//! - Absolute paths and paths including `..` are rejected.
+//! - Symbolic links pointing outside the destination are rejected.
+//! - Entries placed under a symbolic link already in the destination are rejected.
+//! - Hard links must point to another entry in the destination.
+//! - Device nodes and FIFOs are skipped.
+//! - setuid, setgid, and sticky bits are dropped, and ownership and extended attributes are not restored.
+//! - Total size of unpacked files is capped.
+
+use crate::{
+    digest::DigestExt,
+    image::{FileEntry, FileType},
+};
+use anyhow::{bail, Context, Result};
+use oci_spec::image::Digest;
+use std::{
+    collections::HashSet,
+    fs,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+/// Default cap of the total size of files unpacked from an artifact, 8 GiB
+pub const DEFAULT_MAX_UNPACK_SIZE: u64 = 8 * 1024 * 1024 * 1024;
+
+/// Unpack tar archives into a directory with checks described in the module document
+pub(crate) struct Unpacker<'a> {
+    dest: &'a Path,
+    remaining: u64,
+    max_size: u64,
+}
+
+impl<'a> Unpacker<'a> {
+    pub fn new(dest: &'a Path, max_size: u64) -> Self {
+        Self {
+            dest,
+            remaining: max_size,
+            max_size,
+        }
+    }
+
+    /// Unpack entries accepted by `filter`, and returns their relative paths
+    pub fn unpack<R: Read>(
+        &mut self,
+        reader: R,
+        filter: impl Fn(&Path) -> bool,
+    ) -> Result<Vec<PathBuf>> {
+        let mut ar = tar::Archive::new(reader);
+        let mut unpacked = Vec::new();
+        for entry in ar.entries()? {
+            let mut entry = entry?;
+            let path = normalize(&entry.path()?)?;
+            if path.as_os_str().is_empty() || !filter(&path) {
+                continue;
+            }
+            check_parents(self.dest, &path)?;
+            let header = entry.header();
+            let entry_type = header.entry_type();
+            match entry_type {
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let size = header.size()?;
+                    self.remaining = self.remaining.checked_sub(size).with_context(|| {
+                        format!(
+                            "Total size of unpacked files exceeds the limit {} bytes",
+                            self.max_size
+                        )
+                    })?;
+                }
+                tar::EntryType::Directory => {}
+                tar::EntryType::Symlink => {
+                    let target = entry
+                        .link_name()?
+                        .with_context(|| format!("Symlink without target: {}", path.display()))?;
+                    check_symlink(self.dest, &path, &target)?;
+                }
+                tar::EntryType::Link => {
+                    let target = entry
+                        .link_name()?
+                        .with_context(|| format!("Hard link without target: {}", path.display()))?;
+                    let target = normalize(&target)
+                        .with_context(|| format!("Hard link points outside: {}", path.display()))?;
+                    check_parents(self.dest, &target)?;
+                }
+                tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo => {
+                    log::warn!("Skip device file or FIFO: {}", path.display());
+                    continue;
+                }
+                // PAX and GNU extension headers are handled by `tar` crate
+                _ => continue,
+            }
+            entry.set_preserve_permissions(false);
+            entry.set_unpack_xattrs(false);
+            if !entry
+                .unpack_in(self.dest)
+                .with_context(|| format!("Failed to unpack {}", path.display()))?
+            {
+                bail!("Entry escapes destination: {}", path.display());
+            }
+            unpacked.push(path);
+        }
+        Ok(unpacked)
+    }
+}
+
+/// Normalize a relative path in archive, rejecting absolute paths and `..`
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => out.push(c),
+            Component::CurDir => {}
+            Component::ParentDir => bail!("Path including `..` is not allowed: {}", path.display()),
+            Component::RootDir | Component::Prefix(_) => {
+                bail!("Absolute path is not allowed: {}", path.display())
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Check that no parent directory of `path` in `dest` is a symbolic link
+///
+/// [check_symlink] only sees the path in archive, and a link created through another link,
+/// e.g. `d/a -> ..` followed by `d/a/l -> ../x`, would escape the destination.
+fn check_parents(dest: &Path, path: &Path) -> Result<()> {
+    let mut current = dest.to_path_buf();
+    if let Some(parent) = path.parent() {
+        for component in parent.components() {
+            current.push(component);
+            if fs::symlink_metadata(&current).is_ok_and(|meta| meta.is_symlink()) {
+                bail!("Entry is placed under a symlink: {}", path.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that a symbolic link at `path` pointing to `target` stays in the destination
+///
+/// The target is resolved lexically, and must not go through another symbolic link in `dest`,
+/// e.g. `l -> d/b/..` with `d/b -> ..` unpacked before, since `..` is resolved after following it.
+fn check_symlink(dest: &Path, path: &Path, target: &Path) -> Result<()> {
+    // The directory containing the link
+    let mut current: PathBuf = path.parent().unwrap_or(Path::new("")).to_owned();
+    let mut components = target.components().peekable();
+    while let Some(component) = components.next() {
+        match component {
+            Component::Normal(c) => {
+                current.push(c);
+                if components.peek().is_some()
+                    && fs::symlink_metadata(dest.join(&current)).is_ok_and(|m| m.is_symlink())
+                {
+                    bail!(
+                        "Symlink target goes through another symlink: {} -> {}",
+                        path.display(),
+                        target.display()
+                    );
+                }
+            }
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !current.pop() {
+                    bail!(
+                        "Symlink points outside: {} -> {}",
+                        path.display(),
+                        target.display()
+                    );
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => bail!(
+                "Symlink to absolute path is not allowed: {} -> {}",
+                path.display(),
+                target.display()
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Check unpacked entries in `dest` match the list in the ocipkg config
+///
+/// If `complete` is true, i.e. the config records every entry (version 2 or later),
+/// entries not listed in the config are also rejected.
+pub(crate) fn verify_unpacked<'e>(
+    dest: &Path,
+    entries: impl Iterator<Item = &'e FileEntry>,
+    unpacked: &[PathBuf],
+    complete: bool,
+) -> Result<()> {
+    let mut listed = HashSet::new();
+    for entry in entries {
+        let path = normalize(&entry.path)?;
+        verify_entry(&dest.join(&path), entry)?;
+        listed.insert(path);
+    }
+    if complete {
+        for path in unpacked {
+            if !listed.contains(path) {
+                bail!("Unpacked entry is not listed in config: {}", path.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check a file in local filesystem matches the metadata recorded in [FileEntry]
+pub(crate) fn verify_entry(path: &Path, entry: &FileEntry) -> Result<()> {
+    let meta = fs::symlink_metadata(path)
+        .with_context(|| format!("Listed in config but missing: {}", entry.path.display()))?;
+    let Some(file_type) = entry.file_type else {
+        return Ok(());
+    };
+    let matched = match file_type {
+        FileType::File => meta.is_file(),
+        FileType::Directory => meta.is_dir(),
+        FileType::Symlink => meta.is_symlink(),
+    };
+    if !matched {
+        bail!(
+            "Type mismatch of {}: expected {:?}",
+            entry.path.display(),
+            file_type
+        );
+    }
+    if let Some(size) = entry.size {
+        if meta.len() != size {
+            bail!(
+                "Size mismatch of {}: expected {size}, actual {}",
+                entry.path.display(),
+                meta.len()
+            );
+        }
+    }
+    if let Some(digest) = &entry.digest {
+        let actual = Digest::eval_sha256_digest(&fs::read(path)?);
+        if &actual != digest {
+            bail!(
+                "Digest mismatch of {}: expected {digest}, actual {actual}",
+                entry.path.display()
+            );
+        }
+    }
+    if let Some(target) = &entry.target {
+        let actual = fs::read_link(path)?;
+        if &actual != target {
+            bail!(
+                "Symlink target mismatch of {}: expected {}, actual {}",
+                entry.path.display(),
+                target.display(),
+                actual.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(build: impl FnOnce(&mut tar::Builder<Vec<u8>>) -> Result<()>) -> Result<Vec<u8>> {
+        let mut ar = tar::Builder::new(Vec::new());
+        build(&mut ar)?;
+        Ok(ar.into_inner()?)
+    }
+
+    fn header(entry_type: tar::EntryType, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header
+    }
+
+    /// `tar::Builder` rejects unsafe paths, thus write the path into header directly
+    fn append_raw(
+        ar: &mut tar::Builder<Vec<u8>>,
+        mut header: tar::Header,
+        path: &str,
+        link: Option<&str>,
+        data: &[u8],
+    ) -> Result<()> {
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        if let Some(link) = link {
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        }
+        header.set_cksum();
+        ar.append(&header, data)?;
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unsafe_entries() -> Result<()> {
+        let cases = [
+            archive(|ar| append_raw(ar, header(tar::EntryType::Regular, 1), "../x", None, b"x"))?,
+            archive(|ar| append_raw(ar, header(tar::EntryType::Regular, 1), "/x", None, b"x"))?,
+            archive(|ar| {
+                append_raw(
+                    ar,
+                    header(tar::EntryType::Symlink, 0),
+                    "a/l",
+                    Some("../../x"),
+                    b"",
+                )
+            })?,
+            archive(|ar| {
+                append_raw(
+                    ar,
+                    header(tar::EntryType::Symlink, 0),
+                    "l",
+                    Some("/etc"),
+                    b"",
+                )
+            })?,
+            archive(|ar| append_raw(ar, header(tar::EntryType::Regular, 4), "big", None, b"xxxx"))?,
+            archive(|ar| {
+                append_raw(
+                    ar,
+                    header(tar::EntryType::Symlink, 0),
+                    "d/b",
+                    Some(".."),
+                    b"",
+                )?;
+                append_raw(
+                    ar,
+                    header(tar::EntryType::Symlink, 0),
+                    "l",
+                    Some("d/b/.."),
+                    b"",
+                )
+            })?,
+            archive(|ar| {
+                append_raw(
+                    ar,
+                    header(tar::EntryType::Symlink, 0),
+                    "d/a",
+                    Some(".."),
+                    b"",
+                )?;
+                append_raw(
+                    ar,
+                    header(tar::EntryType::Symlink, 0),
+                    "d/a/l",
+                    Some("../x"),
+                    b"",
+                )
+            })?,
+        ];
+        for tar in cases {
+            let tmp_dir = tempfile::tempdir()?;
+            let mut unpacker = Unpacker::new(tmp_dir.path(), 3);
+            assert!(unpacker.unpack(tar.as_slice(), |_| true).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unpack_safe_entries() -> Result<()> {
+        let tar = archive(|ar| {
+            let mut h = header(tar::EntryType::Regular, 1);
+            h.set_mode(0o4755);
+            append_raw(ar, h, "a/x", None, b"x")?;
+            append_raw(
+                ar,
+                header(tar::EntryType::Symlink, 0),
+                "a/l",
+                Some("../b"),
+                b"",
+            )?;
+            append_raw(ar, header(tar::EntryType::Char, 0), "dev", None, b"")?;
+            Ok(())
+        })?;
+        let tmp_dir = tempfile::tempdir()?;
+        let mut unpacker = Unpacker::new(tmp_dir.path(), DEFAULT_MAX_UNPACK_SIZE);
+        let unpacked = unpacker.unpack(tar.as_slice(), |_| true)?;
+        assert_eq!(unpacked, [PathBuf::from("a/x"), PathBuf::from("a/l")]);
+        assert!(!tmp_dir.path().join("dev").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(tmp_dir.path().join("a/x"))?
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o7000, 0);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{ImageName, Name, Reference};
+use anyhow::{Context, Result};
+use semver::{Version, VersionReq};
+use std::fmt;
+
+/// Image name whose reference is a [semver] version requirement, e.g. `ghcr.io/termoshtt/lib:^1.2`
+///
+/// The requirement is resolved into the tag of the highest version satisfying it.
+/// Tags are parsed as semver with an optional `v` prefix, e.g. `1.2.0` or `v1.2.0`,
+/// and other tags are ignored.
+///
+/// ```
+/// use ocipkg::ImageNameReq;
+///
+/// let req = ImageNameReq::parse("ghcr.io/termoshtt/lib:^1.2")?.unwrap();
+/// assert_eq!(req.select(["1.2.0", "v1.3.1", "2.0.0", "latest"]), Some("v1.3.1"));
+///
+/// // A valid tag is not a requirement
+/// assert!(ImageNameReq::parse("ghcr.io/termoshtt/lib:1.2.0")?.is_none());
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageNameReq {
+    pub hostname: String,
+    pub port: Option<u16>,
+    pub name: Name,
+    pub req: VersionReq,
+}
+
+impl fmt::Display for ImageNameReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(port) = self.port {
+            write!(f, "{}:{}/{}:{}", self.hostname, port, self.name, self.req)
+        } else {
+            write!(f, "{}/{}:{}", self.hostname, self.name, self.req)
+        }
+    }
+}
+
+impl ImageNameReq {
+    /// Parse an image name whose reference is a version requirement.
+    ///
+    /// Returns `None` if the reference is a valid tag or missing, i.e. it is a usual [ImageName].
+    pub fn parse(name: &str) -> Result<Option<Self>> {
+        let Some((repository, reference)) = name.rsplit_once(':') else {
+            return Ok(None);
+        };
+        if reference.contains('/') || Reference::new(reference).is_ok() {
+            return Ok(None);
+        }
+        let req = VersionReq::parse(reference)
+            .with_context(|| format!("Invalid tag or version requirement: {reference}"))?;
+        let image_name = ImageName::parse(&format!("{repository}:latest"))?;
+        Ok(Some(Self {
+            hostname: image_name.hostname,
+            port: image_name.port,
+            name: image_name.name,
+            req,
+        }))
+    }
+
+    /// Image name in the same repository with the given tag
+    pub fn with_tag(&self, tag: &str) -> Result<ImageName> {
+        Ok(ImageName {
+            hostname: self.hostname.clone(),
+            port: self.port,
+            name: self.name.clone(),
+            reference: Reference::new(tag)?,
+        })
+    }
+
+    /// Check the image is in the same repository and its tag satisfies the requirement
+    pub fn matches(&self, image_name: &ImageName) -> bool {
+        image_name.hostname == self.hostname
+            && image_name.port == self.port
+            && image_name.name == self.name
+            && parse_tag(&image_name.reference).is_some_and(|version| self.req.matches(&version))
+    }
+
+    /// Select the tag of the highest version satisfying the requirement
+    pub fn select<'a>(&self, tags: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+        tags.into_iter()
+            .filter_map(|tag| Some((parse_tag(tag)?, tag)))
+            .filter(|(version, _)| self.req.matches(version))
+            .max_by(|a, b| a.0.cmp(&b.0))
+            .map(|(_, tag)| tag)
+    }
+}
+
+/// Parse a tag as semver with an optional `v` prefix
+pub(crate) fn parse_tag(tag: &str) -> Option<Version> {
+    Version::parse(tag.strip_prefix('v').unwrap_or(tag)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() -> Result<()> {
+        let req = ImageNameReq::parse("localhost:5000/a/b:>=1.2, <2")?.unwrap();
+        assert_eq!(req.hostname, "localhost");
+        assert_eq!(req.port, Some(5000));
+        assert_eq!(req.name.as_str(), "a/b");
+        assert_eq!(req.to_string(), "localhost:5000/a/b:>=1.2, <2");
+        assert!(ImageNameReq::parse("localhost:5000/a/b")?.is_none());
+        assert!(ImageNameReq::parse("ghcr.io/a/b:latest")?.is_none());
+        assert!(ImageNameReq::parse("ghcr.io/a/b:~1.2")?.is_some());
+        assert!(ImageNameReq::parse("ghcr.io/a/b:^x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn select() -> Result<()> {
+        let req = ImageNameReq::parse("ghcr.io/a/b:~1.2")?.unwrap();
+        assert_eq!(
+            req.select(["1.2.0", "1.2.3", "1.3.0", "1.2.4-rc1", "dev"]),
+            Some("1.2.3")
+        );
+        assert_eq!(req.select(["2.0.0"]), None);
+        assert!(req.matches(&ImageName::parse("ghcr.io/a/b:1.2.9")?));
+        assert!(!req.matches(&ImageName::parse("ghcr.io/a/c:1.2.9")?));
+        Ok(())
+    }
+}
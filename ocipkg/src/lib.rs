@@ -53,73 +53,27 @@ pub extern crate oci_spec;
 
 #[cfg(feature = "remote")]
 pub mod distribution;
+pub mod encryption;
 pub mod image;
 pub mod local;
+pub mod lock;
 pub mod media_types;
 
 mod digest;
 mod image_name;
+mod image_name_req;
+mod library;
 mod name;
 mod reference;
 
 pub use image_name::ImageName;
+pub use image_name_req::ImageNameReq;
 pub use name::Name;
 pub use oci_spec::image::Digest;
 pub use reference::Reference;
 
 #[cfg(feature = "remote")]
-mod link_support {
-    use crate::{distribution, local, ImageName};
-    use anyhow::{Context, Result};
-    use std::fs;
-
-    const STATIC_PREFIX: &str = if cfg!(target_os = "windows") {
-        ""
-    } else {
-        "lib"
-    };
-
-    const STATIC_EXTENSION: &str = if cfg!(target_os = "windows") {
-        "lib"
-    } else {
-        "a"
-    };
-
-    /// Get and link package in `build.rs` with [cargo link instructions](https://doc.rust-lang.org/cargo/reference/build-scripts.html#outputs-of-the-build-script).
-    ///
-    /// This is aimed to use in [build script](https://doc.rust-lang.org/cargo/reference/build-scripts.html) a.k.a. `build.rs`.
-    pub fn link_package(image_name: &str) -> Result<()> {
-        let image_name = ImageName::parse(image_name)?;
-        let dir = local::image_dir(&image_name)?;
-        if !dir.exists() {
-            distribution::get_image(&image_name, false)?;
-        }
-        println!("cargo:rustc-link-search={}", dir.display());
-        for path in fs::read_dir(&dir)?.filter_map(|entry| {
-            let path = entry.ok()?.path();
-            path.is_file().then_some(path)
-        }) {
-            let name = path
-                .file_stem()
-                .unwrap()
-                .to_str()
-                .context("Non UTF-8 path is not supported")?;
-            let name = if let Some(name) = name.strip_prefix(STATIC_PREFIX) {
-                name
-            } else {
-                continue;
-            };
-            if let Some(ext) = path.extension() {
-                if ext == STATIC_EXTENSION {
-                    println!("cargo:rustc-link-lib=static={}", name);
-                }
-            }
-        }
-        println!("cargo:rerun-if-changed={}", dir.display());
-        println!("cargo:rerun-if-env-changed=XDG_DATA_HOME");
-        Ok(())
-    }
-}
+mod link_support;
 
 #[cfg(feature = "remote")]
-pub use link_support::link_package;
+pub use link_support::{link_package, LinkPackage, LinkPreference, LinkedPackage};
@@ -0,0 +1,256 @@
+//! Detect libraries in an unpacked image directory
+
+use crate::image::{Artifact, ArtifactVersion, LinkManifest};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// [LinkManifest] in the config of the image unpacked in `dir`, if declared
+pub(crate) fn read_link_manifest(dir: &Path) -> Result<Option<LinkManifest>> {
+    let mut artifact = Artifact::from_oci_dir(&dir.join(".oci-dir"))?;
+    if artifact.version() == ArtifactVersion::V0 {
+        return Ok(None);
+    }
+    let link = artifact.get_ocipkg_config()?.link().cloned();
+    if let Some(link) = &link {
+        link.validate()
+            .with_context(|| format!("Invalid link manifest in {}", dir.display()))?;
+    }
+    Ok(link)
+}
+
+/// Path in the image directory declared in [LinkManifest], which must not point outside
+pub(crate) fn artifact_path(dir: &Path, path: &Path) -> Result<PathBuf> {
+    if !path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("Path must be relative in the artifact: {}", path.display());
+    }
+    Ok(dir.join(path))
+}
+
+/// Names of regular files in the directory
+pub(crate) fn list_files(dir: &Path) -> Result<Vec<String>> {
+    let mut file_names = Vec::new();
+    if !dir.is_dir() {
+        return Ok(file_names);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let file_name = path.file_name().unwrap();
+        file_names.push(
+            file_name
+                .to_str()
+                .context("Non UTF-8 path is not supported")?
+                .to_string(),
+        );
+    }
+    file_names.sort();
+    Ok(file_names)
+}
+
+/// Platform of the link target, which may differ from the host running `build.rs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Target {
+    Linux,
+    MacOS,
+    Windows,
+}
+
+impl Target {
+    pub fn from_env() -> Self {
+        let os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_else(|_| env::consts::OS.to_string());
+        match os.as_str() {
+            "windows" => Target::Windows,
+            "macos" | "ios" => Target::MacOS,
+            _ => Target::Linux,
+        }
+    }
+}
+
+/// Shared library file to be linked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SharedFile {
+    pub name: String,
+    /// Versioned file, e.g. `libfoo.so.1`, which the linker cannot find by `-lfoo`
+    pub verbatim: bool,
+}
+
+/// Libraries of the same name found in the image directory
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct Library {
+    pub name: String,
+    pub static_: Option<String>,
+    pub shared: Option<SharedFile>,
+    /// All shared library files of this name, including versioned ones, which are required at runtime
+    pub shared_files: Vec<String>,
+}
+
+/// Classify file names in the image directory into libraries for the target
+pub(crate) fn find_libraries(file_names: &[String], target: Target) -> BTreeMap<String, Library> {
+    let mut libraries: BTreeMap<String, Library> = BTreeMap::new();
+    for file_name in file_names {
+        if let Some(name) = static_name(file_name, target) {
+            let lib = libraries.entry(name.to_string()).or_default();
+            lib.name = name.to_string();
+            lib.static_ = Some(file_name.clone());
+        } else if let Some((name, versioned)) = shared_name(file_name, target) {
+            let lib = libraries.entry(name.to_string()).or_default();
+            lib.name = name.to_string();
+            lib.shared_files.push(file_name.clone());
+            lib.shared_files.sort();
+            // Prefer unversioned one, and then the shortest versioned one, e.g. `libfoo.so.1` rather than `libfoo.so.1.2.3`
+            let replace = match &lib.shared {
+                None => true,
+                Some(current) => {
+                    current.verbatim && (!versioned || file_name.len() < current.name.len())
+                }
+            };
+            if replace {
+                lib.shared = Some(SharedFile {
+                    name: file_name.clone(),
+                    verbatim: versioned,
+                });
+            }
+        }
+    }
+    libraries
+}
+
+fn static_name(file_name: &str, target: Target) -> Option<&str> {
+    match target {
+        Target::Windows => file_name.strip_suffix(".lib"),
+        _ => file_name.strip_prefix("lib")?.strip_suffix(".a"),
+    }
+    .filter(|name| !name.is_empty())
+}
+
+/// Library name and whether the file name is versioned
+fn shared_name(file_name: &str, target: Target) -> Option<(&str, bool)> {
+    let (name, versioned) = match target {
+        // Windows links DLLs through import libraries `{name}.lib`, which are not distinguishable from static libraries
+        Target::Windows => return None,
+        Target::MacOS => {
+            let stem = file_name.strip_prefix("lib")?.strip_suffix(".dylib")?;
+            // `libfoo.1.dylib`
+            match stem.split_once('.') {
+                Some((name, version)) if is_version(version) => (name, true),
+                Some(_) => return None,
+                None => (stem, false),
+            }
+        }
+        Target::Linux => {
+            let rest = file_name.strip_prefix("lib")?;
+            let (name, version) = rest.split_once(".so")?;
+            if version.is_empty() {
+                (name, false)
+            } else if is_version(version.strip_prefix('.')?) {
+                (name, true)
+            } else {
+                return None;
+            }
+        }
+    };
+    (!name.is_empty()).then_some((name, versioned))
+}
+
+/// Version suffix of shared library, e.g. `1` or `1.2.3`
+fn is_version(version: &str) -> bool {
+    !version.is_empty()
+        && version
+            .split('.')
+            .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Include directories declared in [LinkManifest], or `include` in the image directory. Only existing ones are returned.
+pub(crate) fn include_paths(dir: &Path, link: Option<&LinkManifest>) -> Result<Vec<PathBuf>> {
+    let paths = match link {
+        Some(link) if !link.include_paths.is_empty() => link
+            .include_paths
+            .iter()
+            .map(|path| artifact_path(dir, path))
+            .collect::<Result<Vec<_>>>()?,
+        _ => vec![dir.join("include")],
+    };
+    Ok(paths.into_iter().filter(|path| path.is_dir()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn libraries(file_names: &[&str], target: Target) -> Vec<Library> {
+        let file_names: Vec<String> = file_names.iter().map(|s| s.to_string()).collect();
+        find_libraries(&file_names, target).into_values().collect()
+    }
+
+    #[test]
+    fn find_linux_libraries() {
+        let libs = libraries(
+            &[
+                "libfoo.a",
+                "libfoo.so",
+                "libbar.so.1.2.3",
+                "libbar.so.1",
+                "libbaz.so.x",
+                "README.md",
+                "lib.a",
+            ],
+            Target::Linux,
+        );
+        assert_eq!(
+            libs,
+            [
+                Library {
+                    name: "bar".into(),
+                    static_: None,
+                    shared: Some(SharedFile {
+                        name: "libbar.so.1".into(),
+                        verbatim: true
+                    }),
+                    shared_files: vec!["libbar.so.1".into(), "libbar.so.1.2.3".into()],
+                },
+                Library {
+                    name: "foo".into(),
+                    static_: Some("libfoo.a".into()),
+                    shared: Some(SharedFile {
+                        name: "libfoo.so".into(),
+                        verbatim: false
+                    }),
+                    shared_files: vec!["libfoo.so".into()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn find_macos_libraries() {
+        let libs = libraries(
+            &["libfoo.dylib", "libfoo.1.dylib", "libfoo.so"],
+            Target::MacOS,
+        );
+        assert_eq!(libs.len(), 1);
+        assert_eq!(
+            libs[0].shared,
+            Some(SharedFile {
+                name: "libfoo.dylib".into(),
+                verbatim: false
+            })
+        );
+        assert_eq!(libs[0].shared_files, ["libfoo.1.dylib", "libfoo.dylib"]);
+    }
+
+    #[test]
+    fn find_windows_libraries() {
+        let libs = libraries(&["foo.lib", "foo.dll", "libbar.a"], Target::Windows);
+        assert_eq!(libs.len(), 1);
+        assert_eq!(libs[0].static_.as_deref(), Some("foo.lib"));
+    }
+}
@@ -0,0 +1,621 @@
+use crate::library::{
+    artifact_path, find_libraries, include_paths, list_files, read_link_manifest, Target,
+};
+use crate::{
+    distribution::{self, Client, StoredAuth},
+    encryption,
+    image::{Artifact, LibraryKind, LinkManifest, Remote},
+    local,
+    lock::{LockFile, LOCK_FILE_NAME},
+    Digest, ImageName, ImageNameReq,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::BTreeSet,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+
+/// Which kind of library is linked when both static and shared libraries of the same name exist
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkPreference {
+    #[default]
+    Static,
+    Dynamic,
+}
+
+/// Get and link package in `build.rs` with [cargo link instructions](https://doc.rust-lang.org/cargo/reference/build-scripts.html#outputs-of-the-build-script).
+///
+/// This is aimed to use in [build script](https://doc.rust-lang.org/cargo/reference/build-scripts.html) a.k.a. `build.rs`.
+/// Static libraries are preferred. Use [LinkPackage] to configure how libraries are linked.
+pub fn link_package(image_name: &str) -> Result<()> {
+    LinkPackage::new(image_name).link()?;
+    Ok(())
+}
+
+/// Builder of link instructions for a package, see [link_package]
+///
+/// ```no_run
+/// use ocipkg::{LinkPackage, LinkPreference};
+///
+/// LinkPackage::new("ghcr.io/termoshtt/ocipkg/dynamic/rust:be7f108")
+///     .prefer(LinkPreference::Dynamic)
+///     .rpath(true)
+///     .link()
+///     .unwrap();
+/// ```
+///
+/// The image directory and include directories are also exposed to crates depending on the crate
+/// with `links` key in `Cargo.toml` as `DEP_{LINKS}_ROOT` and `DEP_{LINKS}_INCLUDE` environment variables,
+/// and custom metadata set by [LinkPackage::metadata] as `DEP_{LINKS}_{KEY}`.
+#[derive(Debug, Clone)]
+pub struct LinkPackage {
+    image_name: String,
+    preference: LinkPreference,
+    rpath: bool,
+    copy_shared: bool,
+    metadata: Vec<(String, String)>,
+    credentials: Option<Credentials>,
+    libraries: Option<Vec<String>>,
+    out_dir: bool,
+    refresh: bool,
+    rerun_if_env_changed: bool,
+    verify: bool,
+    offline: bool,
+}
+
+#[derive(Clone)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+impl LinkPackage {
+    pub fn new(image_name: &str) -> Self {
+        Self {
+            image_name: image_name.to_string(),
+            preference: LinkPreference::default(),
+            rpath: false,
+            copy_shared: false,
+            metadata: Vec::new(),
+            credentials: None,
+            libraries: None,
+            out_dir: false,
+            refresh: false,
+            rerun_if_env_changed: true,
+            verify: false,
+            offline: false,
+        }
+    }
+
+    /// Which kind of library is linked if both exist. Libraries only one kind exists are always linked.
+    pub fn prefer(mut self, preference: LinkPreference) -> Self {
+        self.preference = preference;
+        self
+    }
+
+    /// Embed the image directory into rpath of the output binary,
+    /// so that shared libraries are found at runtime without `LD_LIBRARY_PATH`
+    pub fn rpath(mut self, rpath: bool) -> Self {
+        self.rpath = rpath;
+        self
+    }
+
+    /// Copy linked shared libraries next to the output binary, i.e. `target/{profile}`,
+    /// and embed the directory of the binary into rpath
+    pub fn copy_shared(mut self, copy_shared: bool) -> Self {
+        self.copy_shared = copy_shared;
+        self
+    }
+
+    /// Emit `cargo:{key}={value}` to be propagated as `DEP_{LINKS}_{KEY}` to dependent crates
+    ///
+    /// The key must consist of `[A-Za-z0-9_-]`, and must not be an instruction of cargo, e.g. `rustc-link-lib`.
+    pub fn metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Use the username and password for the registry of the image,
+    /// in addition to the authentication info stored by `ocipkg login`
+    pub fn basic_auth(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some(Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        });
+        self
+    }
+
+    /// Link only these libraries in the artifact, e.g. `["foo"]` for `libfoo.a`. All libraries are linked if not set.
+    ///
+    /// System libraries declared in the artifact are linked regardless of this.
+    pub fn libraries<S: AsRef<str>>(mut self, names: &[S]) -> Self {
+        self.libraries = Some(names.iter().map(|name| name.as_ref().to_string()).collect());
+        self
+    }
+
+    /// Unpack the image under `OUT_DIR` of the build script and link from it, instead of local storage
+    ///
+    /// The image is still fetched into local storage, and blobs are shared with it.
+    pub fn unpack_to_out_dir(mut self, out_dir: bool) -> Self {
+        self.out_dir = out_dir;
+        self
+    }
+
+    /// Re-fetch the image from registry even if it exists in local storage
+    pub fn refresh(mut self, refresh: bool) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    /// Emit `cargo:rerun-if-env-changed` for environment variables determining local storage,
+    /// e.g. `XDG_DATA_HOME` or `OCIPKG_DATA_DIR`. Enabled by default.
+    pub fn rerun_if_env_changed(mut self, rerun: bool) -> Self {
+        self.rerun_if_env_changed = rerun;
+        self
+    }
+
+    /// Check integrity of the image in local storage before linking, see [local::verify_image].
+    /// Corrupted image is re-fetched from registry unless offline.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Never access registries for this package, and use only images in local storage, see [local::is_offline].
+    /// Offline mode is also enabled by `OCIPKG_OFFLINE` environment variable.
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Fetch the image and print link instructions
+    pub fn link(self) -> Result<LinkedPackage> {
+        for (key, value) in &self.metadata {
+            check_metadata(key, value)?;
+        }
+        let lock = LockFile::for_build()?;
+        if let Some((path, _)) = &lock {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+        let image_name = match ImageNameReq::parse(&self.image_name)? {
+            Some(req) => self.resolve(&req, lock.as_ref())?,
+            None => ImageName::parse(&self.image_name)?,
+        };
+        let pinned = match &lock {
+            Some((path, lock)) => {
+                let digest = lock.get(&image_name).with_context(|| {
+                    format!(
+                        "{image_name} is not pinned in {}. Run `ocipkg lock update {image_name}`.",
+                        path.display()
+                    )
+                })?;
+                Some(digest.clone())
+            }
+            None => None,
+        };
+        self.fetch(&image_name, pinned.as_ref(), self.refresh)?;
+        let mut refetched = false;
+        if self.verify {
+            if let Err(e) = local::verify_image(&image_name) {
+                if self.is_offline() {
+                    return Err(e.context(format!("{image_name} in local storage is corrupted")));
+                }
+                println!(
+                    "cargo:warning={image_name} in local storage is corrupted, re-fetching: {e}"
+                );
+                self.fetch(&image_name, pinned.as_ref(), true)?;
+                refetched = true;
+            }
+        }
+        let image_dir = local::image_dir(&image_name)?;
+        let dir = if self.out_dir {
+            let dest = out_dir()?.join("ocipkg").join(image_name.as_path());
+            unpack_to_out_dir(&image_name, &dest, refetched)?;
+            dest
+        } else {
+            image_dir.clone()
+        };
+
+        let target = Target::from_env();
+        let link = read_link_manifest(&dir)?;
+        let shared = match &link {
+            Some(link) => self.emit_manifest(&dir, link, target)?,
+            None => self.emit_guessed(&image_name, &dir, target)?,
+        };
+        if !shared.is_empty() {
+            self.load_shared(&shared, target)?;
+        }
+
+        let include_paths = include_paths(&dir, link.as_ref())?;
+        println!("cargo:root={}", dir.display());
+        if !include_paths.is_empty() {
+            println!(
+                "cargo:include={}",
+                env::join_paths(&include_paths)?.to_string_lossy()
+            );
+        }
+        for (key, value) in &self.metadata {
+            println!("cargo:{key}={value}");
+        }
+
+        // Watch local storage rather than `OUT_DIR`, which is written by this script itself
+        println!("cargo:rerun-if-changed={}", image_dir.display());
+        if self.rerun_if_env_changed {
+            println!("cargo:rerun-if-env-changed=XDG_DATA_HOME");
+            for env in [local::DATA_DIR_ENV, local::HOME_ENV, local::OFFLINE_ENV] {
+                println!("cargo:rerun-if-env-changed={env}");
+            }
+            println!("cargo:rerun-if-env-changed={}", encryption::PRIVATE_KEY_ENV);
+        }
+        Ok(LinkedPackage {
+            image_name,
+            root: dir,
+            include_paths,
+        })
+    }
+
+    /// Link libraries guessed from file names in the top directory of the image,
+    /// and returns shared library files to be loaded at runtime
+    fn emit_guessed(
+        &self,
+        image_name: &ImageName,
+        dir: &Path,
+        target: Target,
+    ) -> Result<Vec<PathBuf>> {
+        let libraries = find_libraries(&list_files(dir)?, target);
+        if libraries.is_empty() {
+            println!("cargo:warning=No library found in {image_name}");
+        }
+
+        println!("cargo:rustc-link-search={}", dir.display());
+        let mut shared = Vec::new();
+        for lib in libraries.values().filter(|lib| self.is_selected(&lib.name)) {
+            let dynamic = match (&lib.static_, &lib.shared) {
+                (Some(_), Some(_)) => self.preference == LinkPreference::Dynamic,
+                (Some(_), None) => false,
+                (None, Some(_)) => true,
+                (None, None) => unreachable!(),
+            };
+            if dynamic {
+                let file = lib.shared.as_ref().unwrap();
+                if file.verbatim {
+                    println!("cargo:rustc-link-lib=dylib:+verbatim={}", file.name);
+                } else {
+                    println!("cargo:rustc-link-lib=dylib={}", lib.name);
+                }
+                shared.extend(lib.shared_files.iter().map(|name| dir.join(name)));
+            } else {
+                println!("cargo:rustc-link-lib=static={}", lib.name);
+            }
+        }
+        Ok(shared)
+    }
+
+    /// Make shared libraries loadable at runtime by rpath or copying them next to the output binary
+    fn load_shared(&self, shared: &[PathBuf], target: Target) -> Result<()> {
+        if self.rpath && target != Target::Windows {
+            let dirs: BTreeSet<&Path> = shared.iter().filter_map(|path| path.parent()).collect();
+            for dir in dirs {
+                println!("cargo:rustc-link-arg=-Wl,-rpath,{}", dir.display());
+            }
+        }
+        if self.copy_shared {
+            let out = output_dir()?;
+            for path in shared {
+                let dest = out.join(path.file_name().unwrap());
+                log::info!("Copy {} into {}", path.display(), out.display());
+                // Remove first since the existing one may be loaded by a running process
+                if dest.exists() {
+                    fs::remove_file(&dest)?;
+                }
+                fs::copy(path, &dest)?;
+            }
+            match target {
+                Target::Linux => println!("cargo:rustc-link-arg=-Wl,-rpath,$ORIGIN"),
+                Target::MacOS => println!("cargo:rustc-link-arg=-Wl,-rpath,@loader_path"),
+                // Windows searches the directory of the executable by default
+                Target::Windows => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve a version requirement from the lock file, the previous resolution in offline mode, or tags in registry
+    fn resolve(&self, req: &ImageNameReq, lock: Option<&(PathBuf, LockFile)>) -> Result<ImageName> {
+        if let Some((path, lock)) = lock {
+            let tags = lock
+                .images()
+                .iter()
+                .filter(|image| req.matches(&image.name))
+                .map(|image| image.name.reference.as_str());
+            let tag = req.select(tags).with_context(|| {
+                format!(
+                    "No image matching {req} is pinned in {}. Run `ocipkg lock update '{req}'`.",
+                    path.display()
+                )
+            })?;
+            return req.with_tag(tag);
+        }
+        if self.is_offline() {
+            return local::load_resolution(req)?.with_context(|| {
+                format!("{req} has never been resolved, and cannot be resolved in offline mode")
+            });
+        }
+        distribution::resolve_version_with_auth(req, self.auth(&req.hostname))
+    }
+
+    /// Fetch the image into local storage unless it is already stored with the pinned digest, or `refresh` is set
+    fn fetch(&self, image_name: &ImageName, pinned: Option<&Digest>, refresh: bool) -> Result<()> {
+        let stored = local::image_dir(image_name)?.exists();
+        if stored && refresh && self.is_offline() {
+            bail!("{image_name} cannot be re-fetched in offline mode");
+        }
+        if stored && !refresh {
+            match pinned {
+                None => return Ok(()),
+                Some(pinned) if &local::image_digest(image_name)? == pinned => return Ok(()),
+                Some(pinned) if self.is_offline() => {
+                    bail!("{image_name} in local storage does not match {pinned} pinned in {LOCK_FILE_NAME}, and cannot be fetched in offline mode")
+                }
+                Some(_) => {}
+            }
+        }
+        local::ensure_online(image_name, self.is_offline())?;
+        let auth = self.auth(&image_name.hostname);
+        let mut remote = Remote::new_with_auth(image_name.clone(), auth.clone())?;
+        if let Some(pinned) = pinned {
+            let digest = Client::from_image_name_with_auth(image_name, auth)?
+                .resolve_digest(&image_name.reference)?;
+            if &digest != pinned {
+                bail!("{image_name} in registry is {digest}, but {pinned} is pinned in {LOCK_FILE_NAME}. Run `ocipkg lock update {image_name}` if the tag is moved intentionally.");
+            }
+            // Fetch by digest not to unpack another manifest even if the tag is moved after the check
+            remote = remote.with_digest(pinned)?;
+        }
+        let mut artifact = Artifact::new(remote)?;
+        if stored {
+            artifact.unpack(true)?;
+        } else {
+            artifact.unpack_if_missing()?;
+        }
+        Ok(())
+    }
+
+    /// Offline mode set by [LinkPackage::offline] only for this package, or enabled globally by [local::is_offline]
+    fn is_offline(&self) -> bool {
+        self.offline || local::is_offline()
+    }
+
+    /// Authentication info stored by `ocipkg login` with [LinkPackage::basic_auth]
+    fn auth(&self, hostname: &str) -> StoredAuth {
+        let mut auth = StoredAuth::load_all().unwrap_or_default();
+        if let Some(credentials) = &self.credentials {
+            auth.add(hostname, &credentials.username, &credentials.password);
+        }
+        auth
+    }
+
+    fn is_selected(&self, name: &str) -> bool {
+        self.libraries
+            .as_ref()
+            .is_none_or(|names| names.iter().any(|selected| selected == name))
+    }
+
+    /// Emit exactly the instructions declared in [LinkManifest],
+    /// and returns shared library files to be loaded at runtime
+    fn emit_manifest(
+        &self,
+        dir: &Path,
+        link: &LinkManifest,
+        target: Target,
+    ) -> Result<Vec<PathBuf>> {
+        let mut search_dirs = Vec::new();
+        for path in &link.search_paths {
+            search_dirs.push(artifact_path(dir, path)?);
+        }
+        if search_dirs.is_empty() {
+            search_dirs.push(dir.to_owned());
+        }
+        for search_dir in &search_dirs {
+            println!("cargo:rustc-link-search=native={}", search_dir.display());
+        }
+
+        let mut shared = Vec::new();
+        for lib in link
+            .libraries
+            .iter()
+            .filter(|lib| self.is_selected(&lib.name))
+        {
+            println!("cargo:rustc-link-lib={lib}");
+            if lib.kind != LibraryKind::Dylib {
+                continue;
+            }
+            for search_dir in &search_dirs {
+                if let Some(found) = find_libraries(&list_files(search_dir)?, target).get(&lib.name)
+                {
+                    shared.extend(found.shared_files.iter().map(|name| search_dir.join(name)));
+                }
+            }
+        }
+        for lib in &link.system_libraries {
+            println!("cargo:rustc-link-lib={lib}");
+        }
+        for cfg in &link.cfgs {
+            println!("cargo:rustc-check-cfg={}", check_cfg(cfg));
+            println!("cargo:rustc-cfg={cfg}");
+        }
+        Ok(shared)
+    }
+}
+
+/// `cfg(...)` expression declaring the cfg as expected, e.g. `cfg(foo, values("bar"))` for `foo="bar"`
+fn check_cfg(cfg: &str) -> String {
+    match cfg.split_once('=') {
+        Some((key, value)) => format!("cfg({}, values({}))", key.trim(), value.trim()),
+        None => format!("cfg({})", cfg.trim()),
+    }
+}
+
+/// Keys of metadata emitted by [LinkPackage::link] itself, and instructions interpreted by cargo
+const RESERVED_METADATA: &[&str] = &[
+    "root",
+    "include",
+    "rerun-if-changed",
+    "rerun-if-env-changed",
+    "rustc-link-arg",
+    "rustc-link-arg-bin",
+    "rustc-link-arg-bins",
+    "rustc-link-arg-tests",
+    "rustc-link-arg-examples",
+    "rustc-link-arg-benches",
+    "rustc-link-arg-cdylib",
+    "rustc-cdylib-link-arg",
+    "rustc-link-lib",
+    "rustc-link-search",
+    "rustc-flags",
+    "rustc-cfg",
+    "rustc-check-cfg",
+    "rustc-env",
+    "warning",
+    "error",
+    "metadata",
+];
+
+fn check_metadata(key: &str, value: &str) -> Result<()> {
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        || RESERVED_METADATA.contains(&key)
+    {
+        bail!("Invalid metadata key: {key:?}");
+    }
+    if value.contains(['\n', '\r']) {
+        bail!("Metadata value must be a single line: {value:?}");
+    }
+    Ok(())
+}
+
+/// Package linked by [LinkPackage::link]
+#[derive(Debug, Clone)]
+pub struct LinkedPackage {
+    image_name: ImageName,
+    root: PathBuf,
+    include_paths: Vec<PathBuf>,
+}
+
+impl LinkedPackage {
+    /// Image name, resolved from the version requirement if given
+    pub fn image_name(&self) -> &ImageName {
+        &self.image_name
+    }
+
+    /// Directory where the image is unpacked, see [local::image_dir]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Include directories declared in the artifact, or `include` in the image directory if exists
+    pub fn include_paths(&self) -> &[PathBuf] {
+        &self.include_paths
+    }
+
+    /// Add the include directories to [cc::Build]
+    ///
+    /// ```no_run
+    /// let package = ocipkg::LinkPackage::new("ghcr.io/termoshtt/ocipkg/static/cpp:e52eae9")
+    ///     .link()
+    ///     .unwrap();
+    /// let mut build = cc::Build::new();
+    /// package.configure_cc(&mut build);
+    /// build.file("src/wrapper.c").compile("wrapper");
+    /// ```
+    #[cfg(feature = "cc")]
+    pub fn configure_cc(&self, build: &mut cc::Build) {
+        build.includes(&self.include_paths);
+    }
+}
+
+fn out_dir() -> Result<PathBuf> {
+    Ok(PathBuf::from(
+        env::var("OUT_DIR").context("OUT_DIR is not set")?,
+    ))
+}
+
+/// Copy the image in local storage into `dest` under `OUT_DIR`
+///
+/// The digest of the copied image is recorded in a stamp file `.{dirname}.digest` next to `dest`,
+/// and the copy is skipped when it matches the image in local storage unless `force`.
+fn unpack_to_out_dir(image_name: &ImageName, dest: &Path, force: bool) -> Result<()> {
+    let digest = local::image_digest(image_name)?;
+    let stamp = dest.with_file_name(format!(
+        ".{}.digest",
+        dest.file_name()
+            .context("Destination must have name")?
+            .to_string_lossy()
+    ));
+    if !force
+        && dest.is_dir()
+        && fs::read_to_string(&stamp).ok().as_deref() == Some(digest.to_string().as_str())
+    {
+        return Ok(());
+    }
+    // Remove the stamp first not to trust a partially replaced `dest`
+    if stamp.exists() {
+        fs::remove_file(&stamp)?;
+    }
+    Artifact::from_oci_dir(&local::image_dir(image_name)?.join(".oci-dir"))?.unpack_to(dest)?;
+    fs::write(&stamp, digest.to_string())?;
+    Ok(())
+}
+
+/// Directory where the output binary is placed, i.e. `target/{profile}`,
+/// determined from `OUT_DIR` which is `target/{profile}/build/{package}-{hash}/out`
+fn output_dir() -> Result<PathBuf> {
+    Ok(out_dir()?
+        .ancestors()
+        .nth(3)
+        .context("Unexpected OUT_DIR layout")?
+        .to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_cfgs() {
+        assert_eq!(check_cfg("has_foo"), "cfg(has_foo)");
+        assert_eq!(
+            check_cfg(r#"foo_version="2""#),
+            r#"cfg(foo_version, values("2"))"#
+        );
+    }
+
+    #[test]
+    fn metadata_keys() {
+        assert!(check_metadata("version", "1.2").is_ok());
+        assert!(check_metadata("foo_dir-2", "/path").is_ok());
+        for key in [
+            "",
+            "root",
+            "rustc-link-lib",
+            "warning",
+            "a=b",
+            "a\ncargo:rustc-link-arg",
+        ] {
+            assert!(check_metadata(key, "x").is_err(), "{key:?}");
+        }
+        assert!(check_metadata("version", "1\ncargo:rustc-link-arg=-evil").is_err());
+    }
+}
@@ -0,0 +1,416 @@
+//! Generate pkg-config and CMake package files for images in local storage
+
+use super::*;
+use crate::{
+    image::{LibraryKind, LinkManifest},
+    library::{
+        artifact_path, find_libraries, include_paths, list_files, read_link_manifest, Target,
+    },
+};
+use std::fmt::Write;
+
+/// Directory where [export_pkg_config] and [export_cmake] write files by default
+///
+/// Set `PKG_CONFIG_PATH` to `{export_dir}/pkgconfig` and `CMAKE_PREFIX_PATH` to `{export_dir}/cmake`
+/// to use the packages in local storage.
+pub fn export_dir() -> Result<PathBuf> {
+    Ok(data_dir()?.join(".export"))
+}
+
+/// A library file in the image directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedLibrary {
+    pub name: String,
+    pub kind: LibraryKind,
+    pub path: PathBuf,
+}
+
+/// Libraries and headers in an image directory to be exported as a pkg-config or CMake package
+#[derive(Debug, Clone)]
+pub struct PackageExport {
+    /// Package name used in `pkg-config` and `find_package`
+    pub name: String,
+    pub version: String,
+    pub description: String,
+    /// Image directory
+    pub prefix: PathBuf,
+    pub include_paths: Vec<PathBuf>,
+    pub libraries: Vec<ExportedLibrary>,
+    /// Libraries provided by the system, see [LinkManifest::system_libraries]
+    pub system_libraries: Vec<String>,
+}
+
+impl PackageExport {
+    /// Collect libraries and headers of an image in local storage
+    ///
+    /// [LinkManifest] is used if the artifact declares it. Otherwise libraries in the top directory
+    /// and headers in `include` directory are exported, and static libraries are preferred.
+    /// The package name is the last component of the image name if `name` is not given.
+    pub fn load(image_name: &ImageName, name: Option<&str>) -> Result<Self> {
+        let dir = image_dir(image_name)?;
+        if !dir.exists() {
+            bail!("Image not found in local storage: {image_name}");
+        }
+        let manifest = OciDir::new(&dir.join(".oci-dir"))?.get_manifest()?;
+        let annotations =
+            Annotations::from_map(manifest.annotations().clone().unwrap_or_default())?;
+        let link = read_link_manifest(&dir)?;
+        Ok(Self {
+            name: name
+                .map(str::to_string)
+                .unwrap_or_else(|| default_package_name(image_name)),
+            version: annotations
+                .version
+                .unwrap_or_else(|| image_name.reference.to_string()),
+            description: annotations
+                .description
+                .unwrap_or_else(|| image_name.to_string()),
+            include_paths: include_paths(&dir, link.as_ref())?,
+            libraries: collect_libraries(&dir, link.as_ref(), Target::from_env())?,
+            system_libraries: link.map(|link| link.system_libraries).unwrap_or_default(),
+            prefix: dir,
+        })
+    }
+
+    /// Contents of `{name}.pc`
+    pub fn to_pkg_config(&self) -> String {
+        let mut pc = String::new();
+        writeln!(pc, "prefix={}", slash(&self.prefix)).unwrap();
+        writeln!(pc).unwrap();
+        writeln!(pc, "Name: {}", self.name).unwrap();
+        writeln!(pc, "Description: {}", self.description).unwrap();
+        writeln!(pc, "Version: {}", self.version).unwrap();
+
+        let mut libs = Vec::new();
+        let mut lib_dirs: Vec<&Path> = Vec::new();
+        for lib in &self.libraries {
+            let dir = lib.path.parent().unwrap();
+            if !lib_dirs.contains(&dir) {
+                lib_dirs.push(dir);
+            }
+        }
+        for dir in lib_dirs {
+            libs.push(format!("-L{}", self.relative(dir)));
+        }
+        for lib in &self.libraries {
+            libs.push(format!("-l{}", lib.name));
+        }
+        let system: Vec<String> = self
+            .system_libraries
+            .iter()
+            .map(|lib| system_library_flag(lib))
+            .collect();
+        // Static libraries always require system libraries, while shared libraries do only for static linking
+        if self
+            .libraries
+            .iter()
+            .any(|lib| lib.kind == LibraryKind::Static)
+        {
+            libs.extend(system);
+        } else if !system.is_empty() {
+            writeln!(pc, "Libs.private: {}", system.join(" ")).unwrap();
+        }
+        if !libs.is_empty() {
+            writeln!(pc, "Libs: {}", libs.join(" ")).unwrap();
+        }
+        let cflags: Vec<String> = self
+            .include_paths
+            .iter()
+            .map(|path| format!("-I{}", self.relative(path)))
+            .collect();
+        if !cflags.is_empty() {
+            writeln!(pc, "Cflags: {}", cflags.join(" ")).unwrap();
+        }
+        pc
+    }
+
+    /// Contents of `{name}Config.cmake`
+    ///
+    /// An imported target `{name}::{library}` is defined for each library,
+    /// and an interface target `{name}::{name}` links all of them.
+    pub fn to_cmake_config(&self) -> String {
+        let includes = cmake_list(self.include_paths.iter().map(|path| slash(path)));
+        let system = cmake_list(self.system_libraries.iter().map(|lib| {
+            system_library_flag(lib)
+                .trim_start_matches("-l")
+                .to_string()
+        }));
+        let mut cmake = String::new();
+        writeln!(
+            cmake,
+            "# Generated by ocipkg: {} {}",
+            self.name, self.version
+        )
+        .unwrap();
+        writeln!(cmake).unwrap();
+        let mut targets = Vec::new();
+        for lib in &self.libraries {
+            let target = format!("{}::{}", self.name, lib.name);
+            let kind = match lib.kind {
+                LibraryKind::Static => "STATIC",
+                LibraryKind::Dylib => "SHARED",
+            };
+            writeln!(cmake, "if(NOT TARGET {target})").unwrap();
+            writeln!(cmake, "  add_library({target} {kind} IMPORTED)").unwrap();
+            writeln!(cmake, "  set_target_properties({target} PROPERTIES").unwrap();
+            writeln!(cmake, "    IMPORTED_LOCATION \"{}\"", slash(&lib.path)).unwrap();
+            if !includes.is_empty() {
+                writeln!(cmake, "    INTERFACE_INCLUDE_DIRECTORIES \"{includes}\"").unwrap();
+            }
+            if !system.is_empty() {
+                writeln!(cmake, "    INTERFACE_LINK_LIBRARIES \"{system}\"").unwrap();
+            }
+            writeln!(cmake, "  )").unwrap();
+            writeln!(cmake, "endif()").unwrap();
+            targets.push(target);
+        }
+
+        let all = format!("{}::{}", self.name, self.name);
+        if !targets.contains(&all) {
+            writeln!(cmake, "if(NOT TARGET {all})").unwrap();
+            writeln!(cmake, "  add_library({all} INTERFACE IMPORTED)").unwrap();
+            writeln!(cmake, "  set_target_properties({all} PROPERTIES").unwrap();
+            if !includes.is_empty() {
+                writeln!(cmake, "    INTERFACE_INCLUDE_DIRECTORIES \"{includes}\"").unwrap();
+            }
+            let links = cmake_list(targets.iter().cloned());
+            if !links.is_empty() {
+                writeln!(cmake, "    INTERFACE_LINK_LIBRARIES \"{links}\"").unwrap();
+            }
+            writeln!(cmake, "  )").unwrap();
+            writeln!(cmake, "endif()").unwrap();
+        }
+        writeln!(cmake).unwrap();
+        writeln!(cmake, "set({}_FOUND TRUE)", self.name).unwrap();
+        writeln!(cmake, "set({}_VERSION \"{}\")", self.name, self.version).unwrap();
+        writeln!(cmake, "set({}_INCLUDE_DIRS \"{includes}\")", self.name).unwrap();
+        writeln!(cmake, "set({}_LIBRARIES {all})", self.name).unwrap();
+        cmake
+    }
+
+    /// Contents of `{name}ConfigVersion.cmake`, accepting any requested version not newer than [PackageExport::version]
+    pub fn to_cmake_config_version(&self) -> String {
+        format!(
+            r#"set(PACKAGE_VERSION "{}")
+if(PACKAGE_FIND_VERSION VERSION_GREATER PACKAGE_VERSION)
+  set(PACKAGE_VERSION_COMPATIBLE FALSE)
+else()
+  set(PACKAGE_VERSION_COMPATIBLE TRUE)
+  if(PACKAGE_FIND_VERSION VERSION_EQUAL PACKAGE_VERSION)
+    set(PACKAGE_VERSION_EXACT TRUE)
+  endif()
+endif()
+"#,
+            self.version
+        )
+    }
+
+    /// Path relative to `${prefix}` in pkg-config file
+    fn relative(&self, path: &Path) -> String {
+        match path.strip_prefix(&self.prefix) {
+            Ok(rel) if rel.as_os_str().is_empty() => "${prefix}".to_string(),
+            Ok(rel) => format!("${{prefix}}/{}", slash(rel)),
+            Err(_) => slash(path),
+        }
+    }
+}
+
+/// Write `{name}.pc` of the image in local storage into `out_dir`, `{export_dir}/pkgconfig` by default
+pub fn export_pkg_config(
+    image_name: &ImageName,
+    name: Option<&str>,
+    out_dir: Option<&Path>,
+) -> Result<PathBuf> {
+    let package = PackageExport::load(image_name, name)?;
+    let out_dir = match out_dir {
+        Some(dir) => dir.to_owned(),
+        None => export_dir()?.join("pkgconfig"),
+    };
+    fs::create_dir_all(&out_dir)?;
+    let path = out_dir.join(format!("{}.pc", package.name));
+    fs::write(&path, package.to_pkg_config())?;
+    Ok(path)
+}
+
+/// Write `{name}Config.cmake` and `{name}ConfigVersion.cmake` of the image in local storage
+/// into `{out_dir}/{name}`, where `out_dir` is `{export_dir}/cmake` by default
+pub fn export_cmake(
+    image_name: &ImageName,
+    name: Option<&str>,
+    out_dir: Option<&Path>,
+) -> Result<PathBuf> {
+    let package = PackageExport::load(image_name, name)?;
+    let out_dir = match out_dir {
+        Some(dir) => dir.to_owned(),
+        None => export_dir()?.join("cmake"),
+    }
+    .join(&package.name);
+    fs::create_dir_all(&out_dir)?;
+    let path = out_dir.join(format!("{}Config.cmake", package.name));
+    fs::write(&path, package.to_cmake_config())?;
+    fs::write(
+        out_dir.join(format!("{}ConfigVersion.cmake", package.name)),
+        package.to_cmake_config_version(),
+    )?;
+    Ok(path)
+}
+
+/// Last component of the image name, e.g. `cpp` for `ghcr.io/termoshtt/ocipkg/static/cpp:e52eae9`
+fn default_package_name(image_name: &ImageName) -> String {
+    let last = image_name
+        .name
+        .as_str()
+        .rsplit('/')
+        .next()
+        .expect("Name is never empty");
+    last.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Library files declared in [LinkManifest], or found in the top directory
+fn collect_libraries(
+    dir: &Path,
+    link: Option<&LinkManifest>,
+    target: Target,
+) -> Result<Vec<ExportedLibrary>> {
+    let mut exported = Vec::new();
+    let Some(link) = link else {
+        for lib in find_libraries(&list_files(dir)?, target).into_values() {
+            let (kind, file) = match (lib.static_, lib.shared) {
+                (Some(file), _) => (LibraryKind::Static, file),
+                (None, Some(shared)) => (LibraryKind::Dylib, shared.name),
+                (None, None) => unreachable!(),
+            };
+            exported.push(ExportedLibrary {
+                name: lib.name,
+                kind,
+                path: dir.join(file),
+            });
+        }
+        return Ok(exported);
+    };
+
+    let mut search_dirs = Vec::new();
+    for path in &link.search_paths {
+        search_dirs.push(artifact_path(dir, path)?);
+    }
+    if search_dirs.is_empty() {
+        search_dirs.push(dir.to_owned());
+    }
+    'libs: for lib in &link.libraries {
+        for search_dir in &search_dirs {
+            let libraries = find_libraries(&list_files(search_dir)?, target);
+            let file = libraries.get(&lib.name).and_then(|found| match lib.kind {
+                LibraryKind::Static => found.static_.clone(),
+                LibraryKind::Dylib => found.shared.as_ref().map(|shared| shared.name.clone()),
+            });
+            if let Some(file) = file {
+                exported.push(ExportedLibrary {
+                    name: lib.name.clone(),
+                    kind: lib.kind,
+                    path: search_dir.join(file),
+                });
+                continue 'libs;
+            }
+        }
+        log::warn!("Library `{lib}` declared in the artifact is not found");
+    }
+    Ok(exported)
+}
+
+/// Linker flag of a system library declared like `stdc++`, `dylib=stdc++`, or `framework=CoreFoundation`
+fn system_library_flag(lib: &str) -> String {
+    match lib.split_once('=') {
+        Some(("framework", name)) => format!("-framework {name}"),
+        Some((_, name)) => format!("-l{name}"),
+        None => format!("-l{lib}"),
+    }
+}
+
+fn slash(path: &Path) -> String {
+    path.display().to_string().replace('\\', "/")
+}
+
+fn cmake_list(items: impl Iterator<Item = String>) -> String {
+    items.collect::<Vec<_>>().join(";")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package() -> PackageExport {
+        PackageExport {
+            name: "foo".into(),
+            version: "1.2.0".into(),
+            description: "ghcr.io/a/foo:1.2.0".into(),
+            prefix: "/data/foo".into(),
+            include_paths: vec!["/data/foo/include".into()],
+            libraries: vec![ExportedLibrary {
+                name: "foo".into(),
+                kind: LibraryKind::Static,
+                path: "/data/foo/lib/libfoo.a".into(),
+            }],
+            system_libraries: vec!["stdc++".into()],
+        }
+    }
+
+    #[test]
+    fn pkg_config() {
+        assert_eq!(
+            package().to_pkg_config(),
+            r#"prefix=/data/foo
+
+Name: foo
+Description: ghcr.io/a/foo:1.2.0
+Version: 1.2.0
+Libs: -L${prefix}/lib -lfoo -lstdc++
+Cflags: -I${prefix}/include
+"#
+        );
+    }
+
+    #[test]
+    fn cmake_config() {
+        let cmake = package().to_cmake_config();
+        assert!(cmake.contains("add_library(foo::foo STATIC IMPORTED)"));
+        assert!(cmake.contains(r#"IMPORTED_LOCATION "/data/foo/lib/libfoo.a""#));
+        assert!(cmake.contains(r#"INTERFACE_LINK_LIBRARIES "stdc++""#));
+        // Library of the same name as the package is used as the package target
+        assert!(!cmake.contains("INTERFACE IMPORTED"));
+        assert!(cmake.contains("set(foo_LIBRARIES foo::foo)"));
+    }
+
+    #[test]
+    fn collect_guessed_libraries() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let dir = tmp_dir.path();
+        for name in ["libfoo.a", "libfoo.so", "libbar.so", "README.md"] {
+            fs::write(dir.join(name), "")?;
+        }
+        let libs = collect_libraries(dir, None, Target::Linux)?;
+        assert_eq!(
+            libs,
+            [
+                ExportedLibrary {
+                    name: "bar".into(),
+                    kind: LibraryKind::Dylib,
+                    path: dir.join("libbar.so"),
+                },
+                ExportedLibrary {
+                    name: "foo".into(),
+                    kind: LibraryKind::Static,
+                    path: dir.join("libfoo.a"),
+                },
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+use super::*;
+use crate::ImageName;
+use anyhow::{bail, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Remove an image from local storage
+///
+/// Blobs in [blob_store_dir] are not removed even if no image refers them. Use [prune] to remove them.
+pub fn remove_image(name: &ImageName) -> Result<()> {
+    let dir = image_dir(name)?;
+    let _lock = lock_image(name)?;
+    if !dir.exists() {
+        bail!("Image not found in local storage: {name}");
+    }
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+/// Conditions of [prune]
+#[derive(Debug, Clone, Default)]
+pub struct PruneOptions {
+    /// Remove images stored before this duration
+    pub older_than: Option<Duration>,
+    /// Keep the N most recently stored images for each repository.
+    /// Other images are removed if `older_than` is not set, or only if they are older than it.
+    pub keep_latest: Option<usize>,
+    /// Remove blobs in [blob_store_dir] not referred by any image
+    pub blobs: bool,
+    /// Only report what would be removed
+    pub dry_run: bool,
+}
+
+/// Result of [prune]
+#[derive(Debug, Clone, Default)]
+pub struct PruneReport {
+    pub images: Vec<ImageName>,
+    pub blobs: Vec<PathBuf>,
+    /// Total size of removed blobs in bytes
+    pub blobs_size: u64,
+}
+
+/// Remove images and unreferenced blobs from local storage
+pub fn prune(opts: &PruneOptions) -> Result<PruneReport> {
+    let images = get_image_list()?
+        .into_iter()
+        .map(|info| (info.name, info.pulled_at.unwrap_or(SystemTime::UNIX_EPOCH)))
+        .collect();
+    let mut report = PruneReport {
+        images: select_prune_targets(images, opts, SystemTime::now()),
+        ..Default::default()
+    };
+    if !opts.dry_run {
+        for name in &report.images {
+            log::info!("Remove {name}");
+            remove_image(name)?;
+        }
+    }
+    if opts.blobs {
+        let store = blob_store_dir()?;
+        // Blobs written into the store but not yet linked into an image must not be removed
+        let _lock = crate::image::lock_blob_store(&store, true)?;
+        for path in unreferenced_blobs(&data_dir()?, &store)? {
+            report.blobs_size += fs::metadata(&path)?.len();
+            if !opts.dry_run {
+                log::info!("Remove {}", path.display());
+                fs::remove_file(&path)?;
+            }
+            report.blobs.push(path);
+        }
+    }
+    Ok(report)
+}
+
+fn select_prune_targets(
+    images: Vec<(ImageName, SystemTime)>,
+    opts: &PruneOptions,
+    now: SystemTime,
+) -> Vec<ImageName> {
+    if opts.older_than.is_none() && opts.keep_latest.is_none() {
+        return Vec::new();
+    }
+    let mut repos: HashMap<String, Vec<(ImageName, SystemTime)>> = HashMap::new();
+    for (name, time) in images {
+        repos
+            .entry(repository(&name))
+            .or_default()
+            .push((name, time));
+    }
+    let mut targets = Vec::new();
+    for (_, mut images) in repos {
+        // Newest first
+        images.sort_by_key(|(_, time)| std::cmp::Reverse(*time));
+        let keep = opts.keep_latest.unwrap_or(0);
+        for (name, time) in images.into_iter().skip(keep) {
+            let old = match opts.older_than {
+                Some(age) => now.duration_since(time).unwrap_or_default() > age,
+                None => true,
+            };
+            if old {
+                targets.push(name);
+            }
+        }
+    }
+    targets.sort_by_key(|name| name.to_string());
+    targets
+}
+
+/// Blobs in `store` which are not in any `.oci-dir` under `data_dir`
+fn unreferenced_blobs(data_dir: &Path, store: &Path) -> Result<Vec<PathBuf>> {
+    if !store.exists() {
+        return Ok(Vec::new());
+    }
+    let mut referenced = HashSet::new();
+    // Walk also temporary directories of images being unpacked now
+    for entry in walkdir::WalkDir::new(data_dir)
+        .into_iter()
+        .filter_entry(|entry| entry.path() != store)
+    {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        if let Some(pos) = path.components().position(|c| c.as_os_str() == ".oci-dir") {
+            let rel: PathBuf = path.components().skip(pos + 1).collect();
+            if rel.starts_with("blobs") {
+                referenced.insert(rel);
+            }
+        }
+    }
+    let mut blobs = Vec::new();
+    for entry in walkdir::WalkDir::new(store).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let rel = entry.path().strip_prefix(store)?;
+        if rel.starts_with("blobs") && !referenced.contains(rel) {
+            blobs.push(entry.path().to_owned());
+        }
+    }
+    Ok(blobs)
+}
+
+/// Disk usage of local storage
+#[derive(Debug, Clone, Default)]
+pub struct DiskUsage {
+    /// Size of each image including its blobs, in bytes
+    pub images: Vec<(ImageName, u64)>,
+    /// Size of [blob_store_dir] in bytes
+    pub blob_store: u64,
+    /// Total size of local storage in bytes. Files shared by hardlinks are counted once.
+    pub total: u64,
+}
+
+/// Compute [DiskUsage] of local storage
+pub fn disk_usage() -> Result<DiskUsage> {
+    Ok(DiskUsage {
+        images: get_image_list()?
+            .into_iter()
+            .map(|info| (info.name, info.size))
+            .collect(),
+        blob_store: dir_size(&blob_store_dir()?)?,
+        total: dir_size(&data_dir()?)?,
+    })
+}
+
+/// Total size of files in `dir`. Files shared by hardlinks are counted once.
+pub(super) fn dir_size(dir: &Path) -> Result<u64> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut seen = HashSet::new();
+    let mut size = 0;
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        if let Some(id) = file_id(&meta) {
+            if !seen.insert(id) {
+                continue;
+            }
+        }
+        size += meta.len();
+    }
+    Ok(size)
+}
+
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_targets() -> Result<()> {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let images = vec![
+            (ImageName::parse("ghcr.io/a/b:1")?, now - 3 * day),
+            (ImageName::parse("ghcr.io/a/b:2")?, now - 2 * day),
+            (ImageName::parse("ghcr.io/a/b:3")?, now),
+            (ImageName::parse("ghcr.io/a/c:1")?, now - 3 * day),
+        ];
+        let names = |opts: &PruneOptions| -> Vec<String> {
+            select_prune_targets(images.clone(), opts, now)
+                .iter()
+                .map(|name| name.to_string())
+                .collect()
+        };
+        assert!(names(&PruneOptions::default()).is_empty());
+        assert_eq!(
+            names(&PruneOptions {
+                keep_latest: Some(1),
+                ..Default::default()
+            }),
+            ["ghcr.io/a/b:1", "ghcr.io/a/b:2"]
+        );
+        assert_eq!(
+            names(&PruneOptions {
+                older_than: Some(day),
+                ..Default::default()
+            }),
+            ["ghcr.io/a/b:1", "ghcr.io/a/b:2", "ghcr.io/a/c:1"]
+        );
+        assert_eq!(
+            names(&PruneOptions {
+                older_than: Some(day),
+                keep_latest: Some(2),
+                ..Default::default()
+            }),
+            ["ghcr.io/a/b:1"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn find_unreferenced_blobs() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let data_dir = tmp_dir.path();
+        let store = data_dir.join(".store");
+        for path in [
+            ".store/blobs/sha256/aaaa",
+            ".store/blobs/sha256/bbbb",
+            "ghcr.io/a/b/__1/.oci-dir/blobs/sha256/aaaa",
+        ] {
+            let path = data_dir.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, "")?;
+        }
+        assert_eq!(
+            unreferenced_blobs(data_dir, &store)?,
+            [store.join("blobs/sha256/bbbb")]
+        );
+        Ok(())
+    }
+}
@@ -1,13 +1,48 @@
 //! Manage container images stored in local storage
 
-use crate::ImageName;
-use anyhow::{anyhow, Result};
+use crate::{
+    image::{annotations::flat::Annotations, Image, OciDir},
+    ImageName, ImageNameReq,
+};
+use anyhow::{anyhow, bail, Context, Result};
 use directories::ProjectDirs;
-use std::{path::*, sync::OnceLock};
+use oci_spec::image::{Digest, ImageIndex, MediaType};
+use std::{
+    fs,
+    path::*,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::SystemTime,
+};
+
+mod export;
+mod manage;
+mod verify;
+
+pub use export::*;
+pub use manage::*;
+pub use verify::*;
 
 pub const DEFAULT_PROJECT_NAME: &str = "ocipkg";
 
+/// Environment variable to specify [data_dir] directly
+pub const DATA_DIR_ENV: &str = "OCIPKG_DATA_DIR";
+
+/// Environment variable to specify the root directory of ocipkg. [data_dir] becomes `$OCIPKG_HOME/data`.
+pub const HOME_ENV: &str = "OCIPKG_HOME";
+
+/// Environment variable to enable offline mode, e.g. `OCIPKG_OFFLINE=1`. See [is_offline].
+pub const OFFLINE_ENV: &str = "OCIPKG_OFFLINE";
+
+/// Annotation of the manifest descriptor in `index.json` of an image in local storage,
+/// recording the digest of the manifest in its source, e.g. registry, see [image_digest].
+pub const SOURCE_DIGEST_ANNOTATION: &str = "vnd.ocipkg.source.digest";
+
 static PROJECT_DIRS: OnceLock<ProjectDirs> = OnceLock::new();
+static OFFLINE: AtomicBool = AtomicBool::new(false);
 
 pub fn set_project_dirs(dirs: ProjectDirs) -> Result<()> {
     PROJECT_DIRS
@@ -16,14 +51,63 @@ pub fn set_project_dirs(dirs: ProjectDirs) -> Result<()> {
 }
 
 /// Project root data directory
+///
+/// This is determined in the following order:
+///
+/// 1. The data directory of [set_project_dirs]
+/// 2. `OCIPKG_DATA_DIR` environment variable
+/// 3. `$OCIPKG_HOME/data` if `OCIPKG_HOME` environment variable is set
+/// 4. The data directory of the platform, e.g. `$XDG_DATA_HOME/ocipkg` on Linux
 pub fn data_dir() -> Result<PathBuf> {
-    // FIXME: use `get_or_try_init` after it is stabilized
-    let p = PROJECT_DIRS.get_or_init(|| {
-        ProjectDirs::from("", DEFAULT_PROJECT_NAME, DEFAULT_PROJECT_NAME)
-            .expect("No valid home directory")
-    });
-    let dir = p.data_dir();
-    Ok(dir.to_owned())
+    if let Some(dirs) = PROJECT_DIRS.get() {
+        return Ok(dirs.data_dir().to_owned());
+    }
+    if let Some(dir) = env_path(DATA_DIR_ENV) {
+        return Ok(std::path::absolute(dir)?);
+    }
+    if let Some(home) = env_path(HOME_ENV) {
+        return Ok(std::path::absolute(home)?.join("data"));
+    }
+    let dirs = ProjectDirs::from("", DEFAULT_PROJECT_NAME, DEFAULT_PROJECT_NAME)
+        .context("No valid home directory")?;
+    Ok(dirs.data_dir().to_owned())
+}
+
+fn env_path(key: &str) -> Option<PathBuf> {
+    std::env::var_os(key)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Enable or disable offline mode in this process
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::Relaxed);
+}
+
+/// Offline mode is enabled by [set_offline] or `OCIPKG_OFFLINE` environment variable
+///
+/// In offline mode, ocipkg never accesses registries, and only images in local storage are available.
+pub fn is_offline() -> bool {
+    if OFFLINE.load(Ordering::Relaxed) {
+        return true;
+    }
+    std::env::var(OFFLINE_ENV).is_ok_and(|value| {
+        matches!(
+            value.trim().to_ascii_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        )
+    })
+}
+
+/// Fail if `offline`, usually [is_offline], since `image_name` is not found in local storage
+pub(crate) fn ensure_online(image_name: &ImageName, offline: bool) -> Result<()> {
+    if offline {
+        bail!(
+            "{image_name} is not found in local storage {}, and cannot be fetched in offline mode ({OFFLINE_ENV})",
+            data_dir()?.display()
+        );
+    }
+    Ok(())
 }
 
 /// Resolve a path to local storage where the image will be stored
@@ -31,25 +115,210 @@ pub fn image_dir(name: &ImageName) -> Result<PathBuf> {
     Ok(data_dir()?.join(name.as_path()))
 }
 
-fn path_to_image_name(path: &Path) -> Result<ImageName> {
+/// Content-addressed store of blobs shared by images in local storage
+///
+/// Blobs are stored as `{blob_store_dir}/blobs/{algorithm}/{digest}`,
+/// and hardlinked into `.oci-dir` of each image.
+pub fn blob_store_dir() -> Result<PathBuf> {
+    Ok(data_dir()?.join(".store"))
+}
+
+/// Digest of the manifest of an image in local storage, as resolved from its source when it is stored
+///
+/// This may differ from the digest of the manifest blob in `.oci-dir`,
+/// since the manifest is re-serialized while copying.
+pub fn image_digest(name: &ImageName) -> Result<Digest> {
+    read_image_digest(&image_dir(name)?.join(".oci-dir"))
+}
+
+fn read_image_digest(oci_dir_root: &Path) -> Result<Digest> {
+    let index: ImageIndex = serde_json::from_slice(&fs::read(oci_dir_root.join("index.json"))?)?;
+    let desc = index
+        .manifests()
+        .first()
+        .context("No manifest found in index.json")?;
+    if let Some(digest) = desc
+        .annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(SOURCE_DIGEST_ANNOTATION))
+    {
+        return Ok(Digest::from_str(digest)?);
+    }
+    Ok(desc.digest().clone())
+}
+
+fn resolution_path(req: &ImageNameReq) -> Result<PathBuf> {
+    Ok(data_dir()?
+        .join(".resolved")
+        .join(urlencoding::encode(&req.to_string()).as_ref()))
+}
+
+/// Record that a version requirement is resolved into an image, see [load_resolution]
+pub fn save_resolution(req: &ImageNameReq, image_name: &ImageName) -> Result<()> {
+    let path = resolution_path(req)?;
+    let parent = path.parent().unwrap();
+    fs::create_dir_all(parent)?;
+    // Write atomically since concurrent builds may read it
+    let mut tmp = tempfile::NamedTempFile::new_in(parent)?;
+    std::io::Write::write_all(&mut tmp, image_name.to_string().as_bytes())?;
+    tmp.persist(&path)?;
+    Ok(())
+}
+
+/// The image resolved from the version requirement by [save_resolution] previously,
+/// which is used to rebuild in offline mode
+pub fn load_resolution(req: &ImageNameReq) -> Result<Option<ImageName>> {
+    let path = resolution_path(req)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(ImageName::parse(fs::read_to_string(path)?.trim())?))
+}
+
+/// Exclusive lock of an image in local storage, released when dropped
+///
+/// This is an advisory lock using a file `.{dirname}.lock` placed next to the image directory,
+/// and is used to serialize writes to the same image by multiple processes, e.g. parallel `cargo build`.
+#[derive(Debug)]
+pub struct ImageLock {
+    _file: fs::File,
+    path: PathBuf,
+}
+
+impl ImageLock {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Acquire [ImageLock] of the image, blocking until other processes release it
+pub fn lock_image(name: &ImageName) -> Result<ImageLock> {
+    let dir = image_dir(name)?;
+    let parent = dir.parent().expect("Image directory always has parent");
+    fs::create_dir_all(parent)?;
+    let path = parent.join(format!(
+        ".{}.lock",
+        dir.file_name()
+            .expect("Image directory always has name")
+            .to_string_lossy()
+    ));
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+    log::debug!("Waiting lock: {}", path.display());
+    file.lock()?;
+    Ok(ImageLock { _file: file, path })
+}
+
+fn path_to_image_name(data_dir: &Path, path: &Path) -> Result<ImageName> {
     let rel_path = path
-        .strip_prefix(data_dir()?)
+        .strip_prefix(data_dir)
         .expect("WalkDir must return path under data_dir");
     ImageName::from_path(rel_path)
 }
 
+/// Image stored in local storage, see [get_image_list]
+#[derive(Debug, Clone)]
+pub struct ImageInfo {
+    pub name: ImageName,
+    /// Image directory, see [image_dir]
+    pub path: PathBuf,
+    /// Total size of files in the image directory in bytes
+    pub size: u64,
+    /// Digest of the image manifest, see [image_digest]
+    pub digest: Option<Digest>,
+    pub artifact_type: Option<MediaType>,
+    /// Time when the image is stored, i.e. modification time of `.oci-dir/index.json`
+    pub pulled_at: Option<SystemTime>,
+    /// `org.opencontainers.image.version` annotation
+    pub version: Option<String>,
+    /// `org.opencontainers.image.revision` annotation
+    pub revision: Option<String>,
+    /// `org.opencontainers.image.source` annotation
+    pub source: Option<String>,
+}
+
+impl ImageInfo {
+    fn load(data_dir: &Path, path: &Path) -> Result<Self> {
+        let mut info = ImageInfo {
+            name: path_to_image_name(data_dir, path)?,
+            path: path.to_owned(),
+            size: dir_size(path)?,
+            digest: None,
+            artifact_type: None,
+            pulled_at: None,
+            version: None,
+            revision: None,
+            source: None,
+        };
+        if let Err(e) = info.read_oci_dir() {
+            log::warn!("Broken image in local storage {}: {e}", path.display());
+        }
+        Ok(info)
+    }
+
+    fn read_oci_dir(&mut self) -> Result<()> {
+        let oci_dir_root = self.path.join(".oci-dir");
+        let index_path = oci_dir_root.join("index.json");
+        self.pulled_at = Some(fs::metadata(&index_path)?.modified()?);
+        let index: ImageIndex = serde_json::from_slice(&fs::read(&index_path)?)?;
+        self.digest = index.manifests().first().map(|desc| desc.digest().clone());
+        let manifest = OciDir::new(&oci_dir_root)?.get_manifest()?;
+        self.artifact_type = manifest.artifact_type().clone();
+        let annotations =
+            Annotations::from_map(manifest.annotations().clone().unwrap_or_default())?;
+        self.version = annotations.version;
+        self.revision = annotations.revision;
+        self.source = annotations.source;
+        Ok(())
+    }
+
+    /// Registry of the image, e.g. `ghcr.io` or `localhost:5000`
+    pub fn registry(&self) -> String {
+        registry(&self.name)
+    }
+
+    /// Repository of the image including registry, e.g. `ghcr.io/termoshtt/ocipkg/dynamic/rust`
+    pub fn repository(&self) -> String {
+        repository(&self.name)
+    }
+}
+
+fn registry(name: &ImageName) -> String {
+    match name.port {
+        Some(port) => format!("{}:{}", name.hostname, port),
+        None => name.hostname.clone(),
+    }
+}
+
+fn repository(name: &ImageName) -> String {
+    format!("{}/{}", registry(name), name.name)
+}
+
 /// Get images stored in local storage
-pub fn get_image_list() -> Result<Vec<ImageName>> {
-    let data_dir = data_dir()?;
+pub fn get_image_list() -> Result<Vec<ImageInfo>> {
+    image_list_in(&data_dir()?)
+}
+
+fn image_list_in(data_dir: &Path) -> Result<Vec<ImageInfo>> {
     if !data_dir.exists() {
         return Ok(Vec::new());
     }
 
     let mut images = Vec::new();
-    for entry in walkdir::WalkDir::new(data_dir) {
+    let mut it = walkdir::WalkDir::new(data_dir)
+        .sort_by_file_name()
+        .into_iter()
+        // Skip shared blob store, lock files, and temporary directories,
+        // but not the data directory itself, which may be hidden e.g. `~/.ocipkg`
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !entry.file_name().to_string_lossy().starts_with('.')
+        });
+    while let Some(entry) = it.next() {
         let entry = entry?;
-        let path = entry.path();
-        if !path.is_dir() {
+        if !entry.file_type().is_dir() {
             continue;
         }
         let name = entry
@@ -57,8 +326,27 @@ pub fn get_image_list() -> Result<Vec<ImageName>> {
             .to_str()
             .expect("Non UTF-8 path is never created in data directory");
         if name.starts_with("__") {
-            images.push(path_to_image_name(path)?);
+            images.push(ImageInfo::load(data_dir, entry.path())?);
+            it.skip_current_dir();
         }
     }
     Ok(images)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_list_in_hidden_data_dir() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let data_dir = tmp_dir.path().join(".ocipkg");
+        let name = ImageName::parse("ghcr.io/termoshtt/test:1.0")?;
+        fs::create_dir_all(data_dir.join(name.as_path()))?;
+        fs::create_dir_all(data_dir.join(".store/blobs"))?;
+        let images = image_list_in(&data_dir)?;
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].name, name);
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use super::*;
+use crate::image::{unpack::verify_unpacked, Artifact, ArtifactVersion, OciDir, CONFIG_VERSION};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Check integrity of an image directory in local storage
+///
+/// - Blobs in `.oci-dir` match the digests, and index.json, manifest, and config are consistent,
+///   see [OciDir::verify].
+/// - Unpacked files match the ocipkg [crate::image::Config] for v1 artifacts.
+///   Files not listed in the config are also rejected if the config is version 2 or later.
+pub fn verify_image_dir(dir: &Path) -> Result<()> {
+    let mut oci_dir = OciDir::new(&dir.join(".oci-dir"))?;
+    oci_dir.verify()?;
+    let mut artifact = Artifact::new(oci_dir)?;
+    if artifact.version() == ArtifactVersion::V0 {
+        return Ok(());
+    }
+    let config = artifact
+        .get_ocipkg_config()
+        .context("Invalid ocipkg config")?;
+    let complete = config.version() >= CONFIG_VERSION;
+    let unpacked = unpacked_entries(dir)?;
+    match verify_unpacked(dir, config.entries(), &unpacked, complete) {
+        Ok(()) => Ok(()),
+        Err(e) if complete => Err(e),
+        Err(e) => {
+            log::warn!("Unpacked files do not match to the legacy config: {e}");
+            Ok(())
+        }
+    }
+}
+
+/// Check integrity of an image in local storage, see [verify_image_dir]
+pub fn verify_image(name: &ImageName) -> Result<()> {
+    let _lock = lock_image(name)?;
+    verify_image_dir(&image_dir(name)?)
+}
+
+/// Re-fetch an image from registry to replace the corrupted one in local storage
+///
+/// Corrupted blobs in the shared blob store are detected and replaced during re-fetch.
+#[cfg(feature = "remote")]
+pub fn repair_image(name: &ImageName) -> Result<()> {
+    Artifact::from_remote(name.clone())?.unpack(true)?;
+    Ok(())
+}
+
+/// Relative paths of entries in the image directory except `.oci-dir`
+fn unpacked_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+    for entry in walkdir::WalkDir::new(dir)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() != 1 || entry.file_name() != ".oci-dir")
+    {
+        let entry = entry?;
+        entries.push(entry.path().strip_prefix(dir)?.to_owned());
+    }
+    Ok(entries)
+}
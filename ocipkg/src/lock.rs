@@ -0,0 +1,164 @@
+//! Pin image names to manifest digests by `ocipkg.lock`
+//!
+//! A tag in registry may be moved to another manifest, and then the image cached in local storage
+//! may differ from the one used in other environments. `ocipkg.lock` placed next to `Cargo.toml`
+//! records the digest of each image:
+//!
+//! ```toml
+//! version = 1
+//!
+//! [[image]]
+//! name = "ghcr.io/termoshtt/ocipkg/static/rust:d7e9b6b"
+//! digest = "sha256:..."
+//! ```
+//!
+//! This file is created and refreshed by `ocipkg lock update`,
+//! and [crate::link_package] fails if the image does not match the pinned digest.
+
+use crate::{Digest, ImageName};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// File name of lock file
+pub const LOCK_FILE_NAME: &str = "ocipkg.lock";
+
+/// The latest version of [LockFile] schema
+pub const LOCK_FILE_VERSION: u32 = 1;
+
+const HEADER: &str = "# This file is generated by `ocipkg lock update`. Do not edit manually.\n";
+
+/// Contents of `ocipkg.lock`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockFile {
+    version: u32,
+    #[serde(default, rename = "image")]
+    images: Vec<LockedImage>,
+}
+
+/// An image pinned to the digest of its manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockedImage {
+    pub name: ImageName,
+    pub digest: Digest,
+}
+
+impl Default for LockFile {
+    fn default() -> Self {
+        Self {
+            version: LOCK_FILE_VERSION,
+            images: Vec::new(),
+        }
+    }
+}
+
+impl LockFile {
+    pub fn from_toml(input: &str) -> Result<Self> {
+        let lock: Self = toml::from_str(input)?;
+        if lock.version > LOCK_FILE_VERSION {
+            bail!(
+                "Unsupported version of {LOCK_FILE_NAME}: {}. Update ocipkg.",
+                lock.version
+            );
+        }
+        Ok(lock)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(format!("{HEADER}{}", toml::to_string_pretty(self)?))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let input = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::from_toml(&input).with_context(|| format!("Invalid lock file: {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_toml()?)?;
+        Ok(())
+    }
+
+    /// Lock file of the crate being built by cargo, i.e. `$CARGO_MANIFEST_DIR/ocipkg.lock`, if it exists
+    pub fn for_build() -> Result<Option<(PathBuf, Self)>> {
+        let Some(dir) = std::env::var_os("CARGO_MANIFEST_DIR") else {
+            return Ok(None);
+        };
+        let path = PathBuf::from(dir).join(LOCK_FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let lock = Self::load(&path)?;
+        Ok(Some((path, lock)))
+    }
+
+    pub fn images(&self) -> &[LockedImage] {
+        &self.images
+    }
+
+    /// Pinned digest of the image
+    pub fn get(&self, name: &ImageName) -> Option<&Digest> {
+        self.images
+            .iter()
+            .find(|image| &image.name == name)
+            .map(|image| &image.digest)
+    }
+
+    /// Pin the image to the digest, replacing existing one
+    pub fn insert(&mut self, name: ImageName, digest: Digest) {
+        match self.images.iter_mut().find(|image| image.name == name) {
+            Some(image) => image.digest = digest,
+            None => self.images.push(LockedImage { name, digest }),
+        }
+        self.images.sort_by_key(|image| image.name.to_string());
+    }
+
+    /// Resolve digests of images in registry and pin them.
+    /// All images in this lock file are refreshed if `names` is empty.
+    #[cfg(feature = "remote")]
+    pub fn update(&mut self, names: &[ImageName]) -> Result<()> {
+        let names: Vec<ImageName> = if names.is_empty() {
+            self.images.iter().map(|image| image.name.clone()).collect()
+        } else {
+            names.to_vec()
+        };
+        for name in names {
+            let mut client = crate::distribution::Client::from_image_name(&name)?;
+            let digest = client.resolve_digest(&name.reference)?;
+            log::info!("{name} -> {digest}");
+            self.insert(name, digest);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const DIGEST: &str = "sha256:a1b2c3d4a1b2c3d4a1b2c3d4a1b2c3d4a1b2c3d4a1b2c3d4a1b2c3d4a1b2c3d4";
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let mut lock = LockFile::default();
+        let b = ImageName::parse("ghcr.io/termoshtt/b:1")?;
+        let a = ImageName::parse("ghcr.io/termoshtt/a:1")?;
+        lock.insert(b.clone(), Digest::from_str(DIGEST)?);
+        lock.insert(a.clone(), Digest::from_str(DIGEST)?);
+        lock.insert(b.clone(), Digest::from_str(DIGEST)?);
+        assert_eq!(lock.images().len(), 2);
+        assert_eq!(lock.images()[0].name, a);
+
+        let toml = lock.to_toml()?;
+        assert!(toml.starts_with(HEADER));
+        let read = LockFile::from_toml(&toml)?;
+        assert_eq!(read, lock);
+        assert_eq!(read.get(&b), Some(&Digest::from_str(DIGEST)?));
+        assert_eq!(read.get(&ImageName::parse("ghcr.io/termoshtt/b:2")?), None);
+        Ok(())
+    }
+}
@@ -18,3 +18,33 @@ pub fn config_json() -> MediaType {
 pub fn layer_tar_gzip() -> MediaType {
     MediaType::Other("application/vnd.ocipkg.v1.layer.tar+gzip".to_string())
 }
+
+/// The media type used in `layer` descriptor of ocipkg artifact without compression
+///
+/// The content of the descriptor of this type must be a tar of the layer
+pub fn layer_tar() -> MediaType {
+    MediaType::Other("application/vnd.ocipkg.v1.layer.tar".to_string())
+}
+
+/// The media type used in `layer` descriptor of ocipkg artifact compressed by zstd
+///
+/// The content of the descriptor of this type must be a tar.zst of the layer
+pub fn layer_tar_zstd() -> MediaType {
+    MediaType::Other("application/vnd.ocipkg.v1.layer.tar+zstd".to_string())
+}
+
+/// Suffix of media type for encrypted layers, e.g. `application/vnd.ocipkg.v1.layer.tar+gzip+encrypted`
+pub const ENCRYPTED_SUFFIX: &str = "+encrypted";
+
+/// Media type of encrypted layer corresponding to given media type
+pub fn encrypted(media_type: &MediaType) -> MediaType {
+    MediaType::Other(format!("{}{}", media_type, ENCRYPTED_SUFFIX))
+}
+
+/// Media type of the decrypted layer if given media type is of encrypted layer
+pub fn decrypted(media_type: &MediaType) -> Option<MediaType> {
+    media_type
+        .to_string()
+        .strip_suffix(ENCRYPTED_SUFFIX)
+        .map(MediaType::from)
+}